@@ -1,4 +1,4 @@
-import type { LoadData, RateioData, Delivery } from '@customTypes/api_data';
+import type { LoadData, RateioData, Delivery, CancelledNFe, Diagnostic, EmailHeaders } from '@customTypes/api_data';
 
 import { copyToClipboard } from "../utils/clipboard"
 import { useState } from 'react';
@@ -16,6 +16,20 @@ function fixPrice(price:number):string{
 	return price.toString().replaceAll(".",",");
 }
 
+function groupByCode(diagnostics:Diagnostic[]):[string, Diagnostic[]][]{
+	const groups = new Map<string, Diagnostic[]>();
+	diagnostics.forEach((diagnostic) => {
+		groups.set(diagnostic.code, [...(groups.get(diagnostic.code) ?? []), diagnostic]);
+	});
+	return [...groups.entries()];
+}
+
+const severityColor = {
+	error: "text-red-600",
+	warning: "text-orange-500",
+	info: "text-gray-500",
+};
+
 
 const DataTable = ({
 	deliveries,
@@ -91,15 +105,18 @@ export const DataShow = ({data,error}:DataShowProps) => {
   const carriers = Object.keys(loads);
   if(carriers.length <= 0) return <p className="text-4xl p-10">Nenhuma transportadora!</p>;
 
-  const errors : string[] = !data ? [] : data.errors;
+  const errors : Diagnostic[] = !data ? [] : data.errors;
+  const cancelled : CancelledNFe[] = !data ? [] : data.cancelled;
+  const email : EmailHeaders | null = !data ? null : data.email;
 
   return <div className="p-5 h-full">
+		{email && <p className="text-xs italic mb-5">Email: {email.subject} - {email.sender} - {email.date}</p>}
 		<ul className="max-h-3/4 min-h-3/4 overflow-scroll">{
 		  carriers.map((carrier) => {
-			const {loads:LoadsData, sequence, email} = loads[carrier];
+			const {carrier:carrierName, loads:LoadsData, sequence, email} = loads[carrier];
 
 			return <li key={carrier}>
-				<h1 className="text-3xl sticky top-0 bg-white mb-5">Cargas - {carrier}</h1>
+				<h1 className="text-3xl sticky top-0 bg-white mb-5">Cargas - {carrierName || carrier}</h1>
 				<pre className="text-xs italic cursor-copy mb-5" onClick={() => copyToClipboard(email)}>{email}</pre>
 
 				{(sequence.length <= 0) ? 
@@ -112,6 +129,7 @@ export const DataShow = ({data,error}:DataShowProps) => {
 						return <div key={loadNumber} className="p-5 mb-10 ">
 							<header className="mb-5">
 								<h1 className="text-xl bold">Carga {String(loadNumber)}</h1>
+								{loadData.incomplete && <p className="text-xs text-red-600">Carga incompleta: há arquivos que não puderam ser lidos</p>}
 								<h2 className="text-xs italic">{loadData.license_plate.toUpperCase()} - R$ {fixPrice(loadData.total_price)} - {loadData.total_cubicage}</h2>
 							</header>
 							<DataTable deliveries={deliveries}/>
@@ -124,11 +142,28 @@ export const DataShow = ({data,error}:DataShowProps) => {
 		  }</ul>
 
 	  <footer className="max-h-1/4 overflow-scroll">
+		  {cancelled.length > 0 && <>
+			  <h1 className="text-2xl text-orange-500 sticky top-0 bg-white">NF-es canceladas</h1>
+			  <ul className="mb-5">
+				  {cancelled.map((nfe) => <li key={nfe.key}>
+					  {nfe.danfe || nfe.key} {nfe.to} {nfe.load_number != null ? `- Carga ${nfe.load_number}` : ""} {nfe.justification}
+				  </li>)}
+			  </ul>
+		  </>}
 		  <h1 className="text-2xl text-red-500 sticky top-0 bg-white">Erros durante o rateio</h1>
 		  {errors.length <= 0 ? 
 			<p>Nenhum erro encontrado!</p> :
 			  <ul>
-				  {errors.map((error,i) => <li key={i}>{error}</li>)}
+				  {groupByCode(errors).map(([code, diagnostics]) => <li key={code} className="mb-2">
+					  <h2 className="font-bold">{code} ({diagnostics.length})</h2>
+					  <ul>
+						  {diagnostics.map((diagnostic,i) => <li key={i} className={severityColor[diagnostic.severity]}>
+							  {diagnostic.message}
+							  {diagnostic.file && <span className="text-xs italic"> - {diagnostic.file}{diagnostic.position != null ? `:${diagnostic.position}` : ""}</span>}
+							  {diagnostic.load_number != null && <span className="text-xs italic"> - Carga {diagnostic.load_number}</span>}
+						  </li>)}
+					  </ul>
+				  </li>)}
 			  </ul>
 		}
 	  </footer>
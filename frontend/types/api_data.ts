@@ -13,15 +13,58 @@ export type Load = {
   license_plate: string;
   total_price: number;
   total_cubicage: number;
+  total_value: number;
+  total_weight: number;
+  incomplete: boolean;
+};
+
+export type Address = {
+	street: string;
+	number: string;
+	complement: string;
+	district: string;
+	city_code: string;
+	city: string;
+	state: string;
+	zip_code: string;
+};
+
+export type Party = {
+	name: string;
+	cnpj: string;
+	cpf: string;
+	ie: string;
+	address: Address;
+};
+
+export type Volume = {
+	quantity: number | null;
+	species: string;
+	brand: string;
+	net_weight: number | null;
+	gross_weight: number | null;
+};
+
+export type DocumentNumber = {
+	series: number;
+	number: number;
 };
 
 export type Delivery = {
 	danfe: string[];
+	document: DocumentNumber[];
 	key: string[];
 	to: string;
+	recipient: Party;
+	delivery_place: Party | null;
 	quantity: number;
 	price: number;
 	cubicage: number;
+	value: number;
+	net_weight: number;
+	gross_weight: number;
+	volumes: Volume[];
+	corrections: string[];
 };
 
 
@@ -30,6 +73,7 @@ export type LoadData = {
 };
 
 export type DataByCarrier = {
+  carrier: string,
   loads: LoadsByNumber,
   sequence: number[],
   email: string
@@ -39,7 +83,37 @@ export type LoadsByNumber = {
 	[key: number]: Load;
 };
 
+export type CancelledNFe = {
+	key: string;
+	danfe: string;
+	to: string;
+	load_number: number | null;
+	justification: string;
+	cancelled_at: string;
+};
+
+export type Severity = "error" | "warning" | "info";
+
+export type Diagnostic = {
+	severity: Severity;
+	code: string;
+	message: string;
+	file: string | null;
+	key: string | null;
+	load_number: number | null;
+	field: string | null;
+	position: number | null;
+};
+
+export type EmailHeaders = {
+	sender: string;
+	date: string;
+	subject: string;
+};
+
 export type RateioData = {
   loads: LoadData;
-  errors: string[];
+  cancelled: CancelledNFe[];
+  errors: Diagnostic[];
+  email: EmailHeaders | null;
 };
@@ -1,7 +1,8 @@
 use std::env;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
-use actix_web::{web, post, head, App, HttpResponse, HttpServer, Responder, http::StatusCode};
+use actix_web::{web, post, head, get, delete, App, HttpResponse, HttpServer, Responder, http::StatusCode};
 use actix_web::http::header;
 use actix_cors::Cors;
 
@@ -9,14 +10,22 @@ use serde::{Serialize};
 
 use log::error;
 
-use rateio::data::parsing::{parse_multiple, parse_email, concat_data};
+use rateio::data::parsing::{parse_multiple, parse_multiple_cached, parse_email_bytes, concat_data, flag_incomplete};
+use rateio::cache::{self, ParseCache};
+use rateio::catalog::ProductCatalog;
 use rateio::files::get_xml_files;
+use rateio::config::Config;
 use rateio::types::Packet;
 
 type PortNumber = u16;
 
+// a saved .eml carries its attachments, most mail providers cap messages at 25MB
+const BODY_LIMIT:usize = 25 * 1024 * 1024;
+
 struct DataState{
-    data_path: PathBuf
+    data_path: PathBuf,
+    config: Config,
+    cache: Option<Mutex<ParseCache>>
 }
 
 #[derive(Serialize)]
@@ -24,40 +33,72 @@ struct ErrorState {
     msg: String
 }
 
-#[post("/data")]
-async fn get_data(data:web::Data<DataState>, body:String) -> impl Responder {
-    let path = data.data_path.clone();
+fn build_packet(data:&DataState, body:&[u8]) -> Packet {
+    let email = parse_email_bytes(body);
 
-    let email_data = match parse_email(&body){
-        Ok(email) => { email },
-        Err(error) => {
-            error!("Failed on parse email: {}",error);
-            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-                .json(ErrorState{msg:"Could not parse email!".to_string()});
-        }
-    };
+    let xml_files = get_xml_files(&data.data_path);
 
-    let xml_files = get_xml_files(&path);
+    let parsed = match &data.cache {
+        Some(cache) => {
+            // the cache is only locked around its lookups, requests still parse side by side
+            let parsed = parse_multiple_cached(&xml_files, &data.config, cache);
+            if let Err(error) = cache::lock(cache).save() {
+                error!("{}", error);
+            }
+            parsed
+        },
+        None => parse_multiple(&xml_files, &data.config)
+    };
+    let (mut loads, second_errors) = concat_data(&parsed.data, &email.data);
+    flag_incomplete(&mut loads, &parsed.incomplete);
+
+    Packet{
+        loads,
+        cancelled: parsed.cancelled,
+        errors: [email.errors, parsed.errors, second_errors].concat(),
+        email: email.headers,
+    }
+}
 
+#[post("/data")]
+// the body is taken as bytes, a saved .eml may be 8bit latin-1 instead of utf-8
+async fn get_data(data:web::Data<DataState>, body:web::Bytes) -> impl Responder {
+    // parsing is CPU bound, it runs off the worker thread
+    let state = data.clone();
+    match web::block(move || build_packet(&state, &body)).await {
+        Ok(packet) => HttpResponse::build(StatusCode::OK).json(packet),
+        Err(error) => {
+            error!("Failed on parse data: {}", error);
+            HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .json(ErrorState{msg:"Could not parse the data!".to_string()})
+        }
+    }
+}
 
-    match parse_multiple(&xml_files){
+#[get("/cache")]
+async fn get_cache(data:web::Data<DataState>) -> impl Responder {
+    match &data.cache {
+        Some(cache) => HttpResponse::Ok().json(cache::lock(cache).summary()),
+        None => HttpResponse::build(StatusCode::NOT_FOUND)
+            .json(ErrorState{msg:"Cache is disabled, set CACHE_PATH to enable it".to_string()})
+    }
+}
 
-        Ok((data, errors)) => {
-            let (loads, second_errors) = concat_data(&data, &email_data);
-            let mut packet = Packet::default();
-            packet.loads = loads;
-            packet.errors = vec![errors.clone(), second_errors.clone()].concat();
+#[delete("/cache")]
+async fn clear_cache(data:web::Data<DataState>) -> impl Responder {
+    let Some(cache) = &data.cache else {
+        return HttpResponse::build(StatusCode::NOT_FOUND)
+            .json(ErrorState{msg:"Cache is disabled, set CACHE_PATH to enable it".to_string()});
+    };
 
-            return HttpResponse::build(StatusCode::OK)
-                .json(packet);
-        },
+    match cache::lock(cache).clear() {
+        Ok(_) => HttpResponse::NoContent().finish(),
         Err(error) => {
-            error!("Failed on parse email: {}",error);
-            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-                .json(ErrorState{msg:"Could not parse data!".to_string()});
+            error!("Failed on clear cache: {}", error);
+            HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .json(ErrorState{msg:"Could not clear the cache!".to_string()})
         }
     }
-
 }
 
 #[head("/health")]
@@ -85,9 +126,33 @@ async fn main() -> std::io::Result<()> {
         Err(e) => panic!("Failed on get DATA_PATH env: {}", e)
     };
 
+    // optional, the defaults are used when it's not set
+    let mut config: Config = match env::var("CONFIG_PATH"){
+        Ok(value) => match Config::from_file(&PathBuf::from(value)){
+            Ok(config) => config,
+            Err(e) => panic!("Failed on load CONFIG_PATH: {}", e)
+        },
+        Err(_) => Config::default()
+    };
+
+    // optional, notes without cubicage in infCpl are left at zero when it's not set
+    if let Ok(value) = env::var("CATALOG_PATH") {
+        config.catalog = match ProductCatalog::from_file(&PathBuf::from(value)){
+            Ok(catalog) => catalog,
+            Err(e) => panic!("Failed on load CATALOG_PATH: {}", e)
+        };
+    }
+
+    // optional, every request parses every file again when it's not set
+    let cache = env::var("CACHE_PATH")
+        .ok()
+        .map(|value| Mutex::new(ParseCache::open(&PathBuf::from(value))));
+
     let state = web::Data::new(
         DataState{
-            data_path : data_path
+            data_path,
+            config,
+            cache
         }
     );
 
@@ -98,14 +163,17 @@ async fn main() -> std::io::Result<()> {
                     .allowed_origin_fn(|origin,_req_head|{
                         origin.as_bytes().starts_with(b"http://localhost")
                     })
-                    .allowed_methods(vec!["HEAD", "POST"])
+                    .allowed_methods(vec!["HEAD", "GET", "POST", "DELETE"])
                     .allowed_header(header::CONTENT_TYPE)
                     .block_on_origin_mismatch(false)
                     .max_age(3600),
             )
             .app_data(state.clone())
+            .app_data(web::PayloadConfig::new(BODY_LIMIT))
             .service(health)
             .service(get_data)
+            .service(get_cache)
+            .service(clear_cache)
     })
     .bind((host, port))?
     .run()
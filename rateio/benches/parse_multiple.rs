@@ -0,0 +1,56 @@
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use rateio::config::Config;
+use rateio::data::parsing::parse_multiple;
+use rateio::files::get_xml_files;
+use rateio::key::check_digit;
+
+const FILES:usize = 10_000;
+const LOADS:usize = 200;
+
+/* writes FILES copies of correct.xml, each one with its own nNF and access
+ * key and spread over LOADS loads, the same shape of a month end batch
+ */
+fn generate_batch() -> PathBuf {
+    let dir = env::temp_dir().join(format!("rateio-bench-{}", FILES));
+    if dir.exists() && fs::read_dir(&dir).map(|entries| entries.count()).unwrap_or(0) == FILES {
+        return dir;
+    }
+
+    fs::create_dir_all(&dir).expect("Failed on create bench dir");
+    let template = fs::read_to_string("./test_data/correct.xml").expect("Failed on read template");
+
+    for index in 0..FILES {
+        let number = 100_000 + index;
+        let without_digit = format!("352401112223330001815500100{:07}1{:08}", number, index);
+        let key = format!("{}{}", without_digit, check_digit(&without_digit));
+
+        let xml = template
+            .replace("35240111222333000181550010000123451000000011", &key)
+            .replace("<nNF>12345</nNF>", &format!("<nNF>{}</nNF>", number))
+            .replace("carga 3245", &format!("carga {}", 1000 + index % LOADS));
+
+        fs::write(dir.join(format!("{:05}.xml", index)), xml).expect("Failed on write bench file");
+    }
+
+    dir
+}
+
+fn bench_parse_multiple(c: &mut Criterion) {
+    let files = get_xml_files(&generate_batch());
+    let config = Config::default();
+
+    let mut group = c.benchmark_group("parse_multiple");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(files.len() as u64));
+    group.bench_function("10k files", |b| b.iter(|| parse_multiple(black_box(&files), &config)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_multiple);
+criterion_main!(benches);
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::classify::Classification;
+use crate::diagnostic::Diagnostic;
+use crate::types::Document;
+
+// bump it whenever the cached types change, older caches are then discarded
+pub const CACHE_VERSION:u32 = 6;
+
+// past it the entries seen the longest ago are dropped
+pub const DEFAULT_CAPACITY:usize = 20_000;
+
+/* what reading a file produced before any config was applied, so changing
+ * the DANFE patterns or the extraction rules doesn't need a fresh parse
+ */
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub enum CachedDocument {
+    Skipped(Classification),
+    Parsed(Document, Vec<Diagnostic>),
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct CacheEntry {
+    // last path seen with this content
+    pub file: String,
+    pub document: CachedDocument,
+    // the run it was last seen on
+    #[serde(default)]
+    pub seen: u64,
+}
+
+#[derive(Debug,Default,Serialize,Deserialize)]
+struct CacheFile {
+    version: u32,
+    #[serde(default)]
+    run: u64,
+    entries: HashMap<String, CacheEntry>,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct CacheSummary {
+    pub path: String,
+    pub entries: usize,
+    pub size: u64,
+    pub files: Vec<CachedFile>,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct CachedFile {
+    pub hash: String,
+    pub file: String,
+    pub document: String,
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Write(io::Error),
+    Serialize(serde_json::Error),
+}
+
+impl fmt::Display for CacheError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            CacheError::Write(error) => write!(f,"Couldn't write parse cache: {}", error),
+            CacheError::Serialize(error) => write!(f,"Couldn't serialize parse cache: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        CacheError::Write(e)
+    }
+}
+
+impl From<serde_json::Error> for CacheError {
+    fn from(e: serde_json::Error) -> Self {
+        CacheError::Serialize(e)
+    }
+}
+
+/* what a run saw: the content hash, the path it was found at and, when it
+ * wasn't in the cache yet, the document it was read as
+ */
+pub struct SeenFile {
+    pub hash: String,
+    pub file: String,
+    pub fresh: Option<CachedDocument>,
+}
+
+#[derive(Debug)]
+pub struct ParseCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    capacity: usize,
+    run: u64,
+    // whether there's anything save has to write
+    changed: bool,
+}
+
+impl ParseCache {
+    // a missing, unreadable or outdated cache file is just an empty cache
+    pub fn open(path:&Path) -> Self {
+        let cache = fs::read(path)
+            .ok()
+            .and_then(|content| serde_json::from_slice::<CacheFile>(&content).ok())
+            .filter(|cache| cache.version == CACHE_VERSION)
+            .unwrap_or_default();
+
+        ParseCache { path: path.to_path_buf(), entries: cache.entries, capacity: DEFAULT_CAPACITY, run: cache.run, changed: false }
+    }
+
+    pub fn with_capacity(mut self, capacity:usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn get(&self, hash:&str) -> Option<&CacheEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn insert(&mut self, hash:String, entry:CacheEntry) {
+        self.entries.insert(hash, entry);
+        self.changed = true;
+    }
+
+    /* stores what was read fresh and keeps the path and run of what came from
+     * the cache. Files that weren't part of the run (or couldn't be read) keep
+     * their entries, they only go once the cache is over its capacity
+     */
+    pub fn record_run(&mut self, files:Vec<SeenFile>) {
+        self.run += 1;
+
+        for SeenFile{ hash, file, fresh } in files {
+            match (fresh, self.entries.get_mut(&hash)) {
+                (Some(document), _) => self.insert(hash, CacheEntry{ file, document, seen: self.run }),
+                (None, Some(entry)) => {
+                    entry.seen = self.run;
+                    if entry.file != file {
+                        entry.file = file;
+                        self.changed = true;
+                    }
+                },
+                (None, None) => (),
+            }
+        }
+
+        self.evict();
+    }
+
+    // the entries seen the longest ago go first, the hash breaks ties so it's deterministic
+    fn evict(&mut self) {
+        if self.entries.len() <= self.capacity {
+            return;
+        }
+
+        let mut by_age : Vec<(u64, String)> = self.entries.iter().map(|(hash, entry)| (entry.seen, hash.clone())).collect();
+        by_age.sort();
+        let excess = self.entries.len() - self.capacity;
+        for (_, hash) in by_age.into_iter().take(excess) {
+            self.entries.remove(&hash);
+        }
+        self.changed = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /* nothing is written when no entry was added, moved or dropped since the
+     * last save, the run counter alone isn't worth rewriting the whole file
+     */
+    pub fn save(&mut self) -> Result<(), CacheError> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let cache = CacheFile { version: CACHE_VERSION, run: self.run, entries: self.entries.clone() };
+        fs::write(&self.path, serde_json::to_vec(&cache)?)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.entries.clear();
+        self.changed = false;
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(())
+        }
+    }
+
+    pub fn summary(&self) -> CacheSummary {
+        let mut files : Vec<CachedFile> = self.entries
+            .iter()
+            .map(|(hash, entry)| CachedFile {
+                hash: hash.clone(),
+                file: entry.file.clone(),
+                document: match &entry.document {
+                    CachedDocument::Skipped(classification) => classification.to_string(),
+                    CachedDocument::Parsed(Document::NFe(_), _) => String::from("NF-e"),
+                    CachedDocument::Parsed(Document::Event(_), _) => String::from("NF-e event"),
+                },
+            })
+            .collect();
+        files.sort_by(|first, second| first.file.cmp(&second.file));
+
+        CacheSummary {
+            path: self.path.display().to_string(),
+            entries: self.entries.len(),
+            size: fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0),
+            files,
+        }
+    }
+}
+
+/* a run that panicked while holding the cache leaves it poisoned, the
+ * entries are still whole documents so it's used as it is
+ */
+pub fn lock(cache:&Mutex<ParseCache>) -> MutexGuard<'_, ParseCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn content_hash(content:&[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+#[cfg(test)]
+mod tests{
+    use std::env;
+
+    use crate::classify::DocumentKind;
+    use crate::types::NFe;
+
+    use super::*;
+
+    fn skipped() -> CachedDocument {
+        CachedDocument::Skipped(Classification{ kind: DocumentKind::Unknown, root: String::new() })
+    }
+
+    fn temporary_cache(name:&str) -> PathBuf {
+        let path = env::temp_dir().join(format!("rateio-cache-test-{}-{}", name, std::process::id())).join("cache.json");
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_content_hash(){
+        assert_eq!(content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(content_hash(b"abc"), content_hash(b"abd"));
+    }
+
+    #[test]
+    fn test_save_open_and_clear() -> Result<(), CacheError>{
+        let path = temporary_cache("save");
+        let mut cache = ParseCache::open(&path);
+        assert!(cache.is_empty());
+
+        let nfe = NFe{ id: String::from("NFe1"), ..Default::default() };
+        cache.insert(String::from("a"), CacheEntry{
+            file: String::from("a.xml"),
+            document: CachedDocument::Parsed(Document::NFe(Box::new(nfe)), vec![]),
+            seen: 0,
+        });
+        cache.insert(String::from("b"), CacheEntry{
+            file: String::from("b.txt"),
+            document: skipped(),
+            seen: 0,
+        });
+        cache.save()?;
+
+        let mut reopened = ParseCache::open(&path);
+        assert_eq!(reopened.len(), 2);
+        match &reopened.get("a").unwrap().document {
+            CachedDocument::Parsed(Document::NFe(nfe), _) => assert_eq!(nfe.id, "NFe1"),
+            other => panic!("unexpected cached document {:?}", other),
+        }
+
+        let summary = reopened.summary();
+        assert_eq!(summary.entries, 2);
+        assert!(summary.size > 0);
+        assert_eq!(summary.files[0].file, "a.xml");
+        assert_eq!(summary.files[0].document, "NF-e");
+        assert_eq!(summary.files[1].document, "unknown document");
+
+        reopened.clear()?;
+        assert!(reopened.is_empty());
+        assert!(!path.exists());
+        assert!(ParseCache::open(&path).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_run_and_capacity() -> Result<(), CacheError>{
+        let path = temporary_cache("capacity");
+        let mut cache = ParseCache::open(&path).with_capacity(2);
+        let seen = |hash:&str, file:&str, fresh:bool| SeenFile{ hash: String::from(hash), file: String::from(file), fresh: fresh.then(skipped) };
+
+        cache.record_run(vec![seen("a", "a.xml", true), seen("b", "b.xml", true)]);
+        assert!(cache.changed);
+        cache.save()?;
+
+        // nothing new, moved or dropped, so there's nothing to write
+        cache.record_run(vec![seen("a", "a.xml", false), seen("missing", "x.xml", false)]);
+        assert!(!cache.changed);
+
+        // same content under another path
+        cache.record_run(vec![seen("b", "moved/b.xml", false)]);
+        assert_eq!(cache.get("b").unwrap().file, "moved/b.xml");
+
+        // over capacity, a (seen on the second run) is older than b (third run)
+        cache.record_run(vec![seen("c", "c.xml", true)]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some() && cache.get("c").is_some());
+
+        cache.save()?;
+        let reopened = ParseCache::open(&path);
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.run, 4);
+        cache.clear()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_outdated_cache_is_discarded(){
+        let path = temporary_cache("outdated");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, r#"{"version": 0, "entries": {"a": {"file": "a.xml", "document": {"Skipped": {"kind": "Unknown", "root": ""}}}}}"#).unwrap();
+        assert!(ParseCache::open(&path).is_empty());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(ParseCache::open(&path).is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::extraction::normalise_decimal;
+use crate::types::{Cubicage, Item, Weight};
+
+/* maps cProd to what a single unit (uCom) of the product takes, used when
+ * the shipper leaves the cubicage out of infCpl. Either a JSON object
+ *
+ *   { "CX-001": { "cubicage": 0.05, "weight": 1.2 } }
+ *
+ * or a CSV with cProd, m³ per unit and kg per unit (`,` or `;` separated,
+ * the header line is optional and decimals may use a comma)
+ */
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+#[serde(transparent)]
+pub struct ProductCatalog {
+    products: HashMap<String, Product>,
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq,Serialize,Deserialize)]
+pub struct Product {
+    // m³ per unit
+    pub cubicage: Cubicage,
+    // kg per unit
+    #[serde(default)]
+    pub weight: Weight,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct Estimate {
+    pub cubicage: Cubicage,
+    pub weight: Weight,
+    // cProd of the items that aren't in the catalog (or have no qCom)
+    pub missing: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Read(std::io::Error),
+    Parse(serde_json::Error),
+    InvalidLine{line: usize, content: String},
+}
+
+impl fmt::Display for CatalogError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            CatalogError::Read(error) => write!(f,"Couldn't read product catalog: {}", error),
+            CatalogError::Parse(error) => write!(f,"Couldn't parse product catalog: {}", error),
+            CatalogError::InvalidLine{line, content} => write!(f,"Invalid product catalog line {}: {}", line, content),
+        }
+    }
+}
+
+impl From<std::io::Error> for CatalogError {
+    fn from(e: std::io::Error) -> Self {
+        CatalogError::Read(e)
+    }
+}
+
+impl From<serde_json::Error> for CatalogError {
+    fn from(e: serde_json::Error) -> Self {
+        CatalogError::Parse(e)
+    }
+}
+
+impl ProductCatalog {
+    // .csv files are read as CSV, anything else as JSON
+    pub fn from_file(file:&Path) -> Result<Self, CatalogError> {
+        let content = fs::read_to_string(file)?;
+        match file.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Self::from_csv(&content),
+            _ => Ok(serde_json::from_str(&content)?)
+        }
+    }
+
+    pub fn from_csv(content:&str) -> Result<Self, CatalogError> {
+        let mut products = HashMap::new();
+
+        for (index, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let separator = if line.contains(';') { ';' } else { ',' };
+            let columns : Vec<&str> = line.split(separator).map(|column| column.trim().trim_matches('"')).collect();
+            let invalid = || CatalogError::InvalidLine{line: index + 1, content: line.to_string()};
+
+            let [code, cubicage, weight @ ..] = columns.as_slice() else {
+                return Err(invalid());
+            };
+            let cubicage = normalise_decimal(cubicage).parse::<Cubicage>();
+            let weight = match weight.first().filter(|weight| !weight.is_empty()) {
+                Some(weight) => normalise_decimal(weight).parse::<Weight>(),
+                None => Ok(0.0)
+            };
+
+            match (cubicage, weight) {
+                (Ok(cubicage), Ok(weight)) => { products.insert(code.to_string(), Product{ cubicage, weight }); },
+                // cProd;m3;kg
+                _ if index == 0 => (),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(ProductCatalog { products })
+    }
+
+    pub fn get(&self, code:&str) -> Option<&Product> {
+        self.products.get(code.trim())
+    }
+
+    pub fn len(&self) -> usize {
+        self.products.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty()
+    }
+
+    // None when no item could be found, a partial estimate lists what was left out
+    pub fn estimate(&self, items:&[Item]) -> Option<Estimate> {
+        let mut estimate = Estimate::default();
+        let mut found = false;
+
+        for item in items {
+            match (self.get(&item.code), item.quantity) {
+                (Some(product), Some(quantity)) => {
+                    estimate.cubicage += product.cubicage * quantity;
+                    estimate.weight += product.weight * quantity;
+                    found = true;
+                },
+                _ => estimate.missing.push(item.code.clone())
+            }
+        }
+
+        found.then_some(estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn item(code:&str, quantity:Option<f32>) -> Item {
+        Item{ code: String::from(code), quantity, ..Default::default() }
+    }
+
+    #[test]
+    fn test_catalog_from_csv() -> Result<(), CatalogError>{
+        let catalog = ProductCatalog::from_csv("cProd;m3;kg\nCX-001;0,05;1,2\n\nPL-002;0,3;15\n")?;
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog.get("CX-001"), Some(&Product{ cubicage: 0.05, weight: 1.2 }));
+
+        let catalog = ProductCatalog::from_csv("CX-001,0.05,1.2\nPL-002,0.3")?;
+        assert_eq!(catalog.get("PL-002"), Some(&Product{ cubicage: 0.3, weight: 0.0 }));
+
+        match ProductCatalog::from_csv("CX-001;0,05\nPL-002;abc") {
+            Err(CatalogError::InvalidLine{line, ..}) => assert_eq!(line, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_catalog_from_file() -> Result<(), CatalogError>{
+        let csv = ProductCatalog::from_file(Path::new("./test_data/catalog.csv"))?;
+        let json = ProductCatalog::from_file(Path::new("./test_data/catalog.json"))?;
+
+        assert_eq!(csv.len(), 2);
+        assert_eq!(csv.get("CX-001"), json.get("CX-001"));
+        assert_eq!(csv.get("PL-002"), json.get("PL-002"));
+        assert!(ProductCatalog::from_file(Path::new("./test_data/missing.csv")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate(){
+        let catalog = ProductCatalog::from_csv("CX-001;0,05;1,2\nPL-002;0,3;15").unwrap();
+
+        let estimate = catalog.estimate(&[item("CX-001", Some(10.0)), item("PL-002", Some(2.0)), item("XX", Some(1.0))]).unwrap();
+        assert!((estimate.cubicage - 1.1).abs() < 1e-5);
+        assert!((estimate.weight - 42.0).abs() < 1e-4);
+        assert_eq!(estimate.missing, vec![String::from("XX")]);
+
+        assert_eq!(catalog.estimate(&[item("XX", Some(1.0)), item("CX-001", None)]), None);
+        assert_eq!(catalog.estimate(&[]), None);
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::*;
+use crate::files::read_file;
+use crate::types::ParseErrors;
+
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub enum DocumentKind {
+    NFe,
+    NFCe,
+    NFeEvent,
+    CTe,
+    CTeEvent,
+    MDFe,
+    MDFeEvent,
+    Unknown,
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct Classification {
+    pub kind: DocumentKind,
+    pub root: String,
+}
+
+impl fmt::Display for DocumentKind{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            DocumentKind::NFe => write!(f,"NF-e"),
+            DocumentKind::NFCe => write!(f,"NFC-e"),
+            DocumentKind::NFeEvent => write!(f,"NF-e event"),
+            DocumentKind::CTe => write!(f,"CT-e"),
+            DocumentKind::CTeEvent => write!(f,"CT-e event"),
+            DocumentKind::MDFe => write!(f,"MDF-e"),
+            DocumentKind::MDFeEvent => write!(f,"MDF-e event"),
+            DocumentKind::Unknown => write!(f,"unknown document"),
+        }
+    }
+}
+
+impl fmt::Display for Classification{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.root.is_empty() {
+            return write!(f,"{}", self.kind);
+        }
+        write!(f,"{} {}", self.kind, self.root)
+    }
+}
+
+impl DocumentKind {
+    // the only kinds that go into the apportionment
+    pub fn is_parsed(&self) -> bool {
+        matches!(self, DocumentKind::NFe | DocumentKind::NFeEvent)
+    }
+}
+
+pub fn classify_file(file:&Path) -> Result<Classification, ParseErrors> {
+    classify_reader(&read_file(file)?[..])
+}
+
+pub fn classify_reader<R: BufRead>(reader:R) -> Result<Classification, ParseErrors> {
+    read_classification(Reader::from_reader(reader))
+}
+
+/* reads only what is needed: the root element, its namespace and, for NF-e,
+ * the ide/mod. Anything that is not even xml is an unknown document.
+ */
+fn read_classification<R: BufRead>(mut reader:Reader<R>) -> Result<Classification, ParseErrors> {
+    let mut root = String::new();
+    let mut namespace = String::new();
+    let mut model = String::new();
+    let mut inside_model = false;
+    let mut buffer = Vec::new();
+
+    loop{
+        match reader.read_event_into(&mut buffer){
+            Err(_) | Ok(Event::Eof) => break,
+            Ok(Event::Start(tag)) | Ok(Event::Empty(tag)) => {
+                let name = String::from_utf8_lossy(tag.local_name().as_ref()).to_string();
+
+                if root.is_empty() {
+                    root = name;
+                    if let Some(xmlns) = tag.try_get_attribute("xmlns")? {
+                        namespace = xmlns.decode_and_unescape_value(reader.decoder())?.to_string();
+                    }
+
+                    if !NFE_ROOT_TAGS.contains(&root.as_str()) {
+                        break;
+                    }
+                    continue;
+                }
+
+                inside_model = name == MODEL_TAG;
+            },
+            Ok(Event::Text(text)) if inside_model => {
+                model = text.decode()?.trim().to_string();
+                break;
+            },
+            Ok(Event::End(tag)) => {
+                // mod is the second tag of ide, no need to go any further
+                if tag.local_name().as_ref() == b"ide" {
+                    break;
+                }
+                inside_model = false;
+            },
+            _ => ()
+        }
+        buffer.clear();
+    }
+
+    Ok(Classification{
+        kind: kind_of(&root, &namespace, &model),
+        root,
+    })
+}
+
+fn kind_of(root:&str, namespace:&str, model:&str) -> DocumentKind {
+    let kind = match root {
+        "nfeProc" | "NFe" if model == NFCE_MODEL => DocumentKind::NFCe,
+        "nfeProc" | "NFe" => DocumentKind::NFe,
+        "procEventoNFe" => DocumentKind::NFeEvent,
+        "cteProc" | "CTe" | "cteOSProc" | "CTeOS" => DocumentKind::CTe,
+        "procEventoCTe" => DocumentKind::CTeEvent,
+        "mdfeProc" | "MDFe" => DocumentKind::MDFe,
+        "procEventoMDFe" => DocumentKind::MDFeEvent,
+        EVENT_TAG => match namespace {
+            CTE_NAMESPACE => DocumentKind::CTeEvent,
+            MDFE_NAMESPACE => DocumentKind::MDFeEvent,
+            _ => DocumentKind::NFeEvent
+        },
+        _ => DocumentKind::Unknown
+    };
+
+    // a namespace that doesn't belong to the root means it's not a fiscal document we know
+    let expected_namespace = match kind {
+        DocumentKind::NFe | DocumentKind::NFCe | DocumentKind::NFeEvent => NFE_NAMESPACE,
+        DocumentKind::CTe | DocumentKind::CTeEvent => CTE_NAMESPACE,
+        DocumentKind::MDFe | DocumentKind::MDFeEvent => MDFE_NAMESPACE,
+        DocumentKind::Unknown => return kind,
+    };
+
+    if !namespace.is_empty() && namespace != expected_namespace {
+        return DocumentKind::Unknown;
+    }
+
+    kind
+}
+
+#[cfg(test)]
+mod tests{
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn classify(xml:&str) -> Classification {
+        classify_reader(xml.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_classify_files() -> Result<(), ParseErrors>{
+        assert_eq!(classify_file(&PathBuf::from("./test_data/correct.xml"))?.kind, DocumentKind::NFe);
+        assert_eq!(classify_file(&PathBuf::from("./test_data/repeated_tags.xml"))?.kind, DocumentKind::NFe);
+        assert_eq!(classify_file(&PathBuf::from("./test_data/cancellation_event.xml"))?.kind, DocumentKind::NFeEvent);
+        assert_eq!(classify_file(&PathBuf::from("./test_data/wrong.xml"))?.kind, DocumentKind::Unknown);
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_model(){
+        let nfce = classify(r#"<nfeProc xmlns="http://www.portalfiscal.inf.br/nfe"><NFe><infNFe><ide><cUF>35</cUF><mod>65</mod></ide></infNFe></NFe></nfeProc>"#);
+        assert_eq!(nfce.kind, DocumentKind::NFCe);
+        assert!(!nfce.kind.is_parsed());
+
+        let nfe = classify(r#"<NFe><infNFe><ide><mod>55</mod></ide></infNFe></NFe>"#);
+        assert_eq!(nfe.kind, DocumentKind::NFe);
+        assert!(nfe.kind.is_parsed());
+    }
+
+    #[test]
+    fn test_classify_other_documents(){
+        let cte = classify(r#"<?xml version="1.0"?><cteProc xmlns="http://www.portalfiscal.inf.br/cte"><CTe/></cteProc>"#);
+        assert_eq!(cte.kind, DocumentKind::CTe);
+        assert_eq!(cte.to_string(), "CT-e cteProc");
+
+        let mdfe = classify(r#"<mdfeProc xmlns="http://www.portalfiscal.inf.br/mdfe"></mdfeProc>"#);
+        assert_eq!(mdfe.kind, DocumentKind::MDFe);
+
+        let cte_event = classify(r#"<evento xmlns="http://www.portalfiscal.inf.br/cte"></evento>"#);
+        assert_eq!(cte_event.kind, DocumentKind::CTeEvent);
+
+        let wrong_namespace = classify(r#"<NFe xmlns="http://example.com"></NFe>"#);
+        assert_eq!(wrong_namespace.kind, DocumentKind::Unknown);
+
+        let not_xml = classify("just some text");
+        assert_eq!(not_xml.kind, DocumentKind::Unknown);
+        assert_eq!(not_xml.to_string(), "unknown document");
+    }
+}
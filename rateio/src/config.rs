@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::ProductCatalog;
+
+/* placeholders accepted in a DANFE pattern:
+ * {series}   - series without leading zeros
+ * {series:N} - series padded with zeros up to N digits
+ * {number}   - nNF without leading zeros
+ * {number:N} - nNF padded with zeros up to N digits
+ */
+pub const DEFAULT_DANFE_PATTERN:&str = "{series:3}000{number}";
+
+/* extraction rules are regexes matched (ignoring case) against infCpl. The
+ * value is taken from the group named `value` (or the first one) and, for
+ * cubicage, the unit from the group named `unit`, so dm3 and cm3 end up in m3
+ */
+const CUBIC_UNITS:&str = "(?P<unit>m3|m³|dm3|dm³|cm3|cm³)";
+const DECIMAL_VALUE:&str = "(?P<value>[0-9]+(?:[.,][0-9]+)*)";
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub danfe: DanfeFormats,
+    pub extraction: ExtractionRules,
+    // loaded from its own file, see ProductCatalog::from_file
+    #[serde(skip)]
+    pub catalog: ProductCatalog,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+#[serde(default)]
+pub struct DanfeFormats {
+    pub default: String,
+    // emitter CNPJ -> pattern
+    pub emitters: HashMap<String, String>,
+}
+
+/* the emitter rules are tried first and the default ones after them, a
+ * `default` set written in the config file replaces the built-in one
+ */
+#[derive(Debug,Clone,Serialize,Deserialize)]
+#[serde(default)]
+pub struct ExtractionRules {
+    pub default: RuleSet,
+    // emitter CNPJ -> rules
+    pub emitters: HashMap<String, RuleSet>,
+}
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+#[serde(default)]
+pub struct RuleSet {
+    pub load: Vec<Rule>,
+    pub cubicage: Vec<Rule>,
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(std::io::Error),
+    Parse(serde_json::Error),
+    ParseToml(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            ConfigError::Read(error) => write!(f,"Couldn't read config file: {}", error),
+            ConfigError::Parse(error) => write!(f,"Couldn't parse config file: {}", error),
+            ConfigError::ParseToml(error) => write!(f,"Couldn't parse config file: {}", error),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Read(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::ParseToml(e)
+    }
+}
+
+impl Default for DanfeFormats {
+    fn default() -> Self {
+        DanfeFormats {
+            default: String::from(DEFAULT_DANFE_PATTERN),
+            emitters: HashMap::new(),
+        }
+    }
+}
+
+impl DanfeFormats {
+    pub fn pattern_for(&self, emitter:&str) -> &str {
+        match self.emitters.get(emitter) {
+            Some(pattern) => pattern,
+            None => &self.default
+        }
+    }
+}
+
+impl Default for ExtractionRules {
+    fn default() -> Self {
+        ExtractionRules {
+            default: RuleSet {
+                load: vec![
+                    Rule::new("carga", r"carga *:* *(?P<value>[0-9]+)"),
+                    Rule::new("numero carga", r"carga *n[º°o.]* *:* *(?P<value>[0-9]+)"),
+                ],
+                cubicage: vec![
+                    Rule::new("cubicagem", &format!(r"cub(?:icagem|\.)? *:* *{} *{}", DECIMAL_VALUE, CUBIC_UNITS)),
+                    Rule::new("volume", &format!(r"volume *:* *{} *{}", DECIMAL_VALUE, CUBIC_UNITS)),
+                ],
+            },
+            emitters: HashMap::new(),
+        }
+    }
+}
+
+impl Rule {
+    pub fn new(name:&str, pattern:&str) -> Self {
+        Rule { name: String::from(name), pattern: String::from(pattern) }
+    }
+}
+
+impl Config {
+    // .toml files are read as TOML, anything else as JSON
+    pub fn from_file(file:&PathBuf) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(file)?;
+        match file.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Ok(toml::from_str(&content)?),
+            _ => Ok(serde_json::from_str(&content)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_danfe_pattern_for_emitter(){
+        let config : Config = serde_json::from_str(r#"{
+            "danfe": {
+                "emitters": { "11222333000181": "{series:3}-{number:9}" }
+            }
+        }"#).unwrap();
+
+        assert_eq!(config.danfe.default, DEFAULT_DANFE_PATTERN);
+        assert_eq!(config.danfe.pattern_for("11222333000181"), "{series:3}-{number:9}");
+        assert_eq!(config.danfe.pattern_for("99888777000166"), DEFAULT_DANFE_PATTERN);
+    }
+
+    #[test]
+    fn test_extraction_rules_from_toml() -> Result<(), ConfigError>{
+        let config = Config::from_file(&PathBuf::from("./test_data/rules.toml"))?;
+
+        let names = |rules:&[Rule]| rules.iter().map(|rule| rule.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&config.extraction.emitters["11222333000181"].load), vec!["romaneio"]);
+        assert!(config.extraction.emitters["11222333000181"].cubicage.is_empty());
+        // a default set in the file replaces the built-in one
+        assert_eq!(names(&config.extraction.default.load), vec!["pedido carga"]);
+        assert_eq!(names(&config.extraction.default.cubicage), vec!["cubagem"]);
+
+        let default = Config::default();
+        assert_eq!(names(&default.extraction.default.load), vec!["carga", "numero carga"]);
+        assert!(default.extraction.emitters.is_empty());
+        Ok(())
+    }
+}
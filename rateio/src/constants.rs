@@ -1,22 +1,29 @@
-use crate::types::TagName;
+// elements used as the root of every path matched inside a NF-e document
+pub const INF_NFE_TAG:&str = "infNFe";
+pub const PROT_NFE_TAG:&str = "protNFe";
 
-pub const DANFE_FLAG:u8 = 0b00000001;
-pub const RAZAO_SOCIAL_FLAG:u8 = 0b00000010;
-pub const SHIPPING_COMPANY_FLAG:u8 = 0b00000100;
-pub const LOAD_CUBICAGE_FLAG:u8 = 0b00001000;
-pub const QUANTITY_FLAG:u8 = 0b00010000;
-pub const ACCESS_KEY_FLAG:u8 = 0b00100000;
+// elements used as the root of every path matched inside an event document
+pub const EVENT_TAG:&str = "evento";
+pub const EVENT_RESULT_TAG:&str = "retEvento";
+pub const EVENT_ROOT_TAGS:[&str; 2] = ["procEventoNFe", EVENT_TAG];
 
-pub const RAZAO_SOCIAL_BACKTRACK_FLAG:u8 = 0b00000001;
-pub const SHIPPING_COMPANY_BACKTRACK_FLAG:u8 = 0b00000010;
+pub const CANCELLATION_EVENT:&str = "110111";
+pub const CORRECTION_LETTER_EVENT:&str = "110110";
+// event registered, registered out of time and cancellation out of time
+pub const REGISTERED_EVENT_STATUS:[&str; 3] = ["135", "136", "155"];
 
-pub const DANFE_TAG:TagName = b"nFat";
-pub const DANFE_TAG_SECOND:TagName = b"nNF";
-pub const LOAD_CUBICAGE_TAG:TagName = b"infCpl";
-pub const QUANTITY_TAG:TagName = b"qVol";
-pub const ACCESS_KEY_TAG:TagName = b"chNFe";
+// used to tell the documents apart before parsing them
+pub const NFE_ROOT_TAGS:[&str; 2] = ["nfeProc", "NFe"];
+pub const MODEL_TAG:&str = "mod";
+pub const NFCE_MODEL:&str = "65";
 
-pub const RAZAO_SOCIAL_FIRST_TAG:TagName = b"dest";
-pub const SHIPPING_COMPANY_FIRST_TAG:TagName = b"transporta";
+pub const NFE_NAMESPACE:&str = "http://www.portalfiscal.inf.br/nfe";
+pub const CTE_NAMESPACE:&str = "http://www.portalfiscal.inf.br/cte";
+pub const MDFE_NAMESPACE:&str = "http://www.portalfiscal.inf.br/mdfe";
 
-pub const X_NOME:&[u8] = b"xNome"; // used for Razao Social and Shipping company
+pub const VOLUME_TAG:&str = "vol";
+pub const ITEM_TAG:&str = "det";
+pub const ITEM_NUMBER_ATTRIBUTE:&[u8] = b"nItem";
+
+pub const ID_ATTRIBUTE:&[u8] = b"Id";
+pub const ACCESS_KEY_ID_PREFIX:&str = "NFe";
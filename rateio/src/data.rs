@@ -1,88 +1,270 @@
 
 
-mod flags{
-    pub fn generate_flags() -> (u8, u8){
-        let flags : u8 = 0b00000000;
-        /* ============CHECK FLAGS======================
-         * the flags start from right to left
-         * first  - DANFE
-         * second - Razao social
-         * third  - Shipping company
-         * forth  - Load and Cubicage fifth  - Quantity
-         * sixth  - Access Key
-         */
-            
-        let backtrack : u8 = 0b00000000;
-        /* ===========BACKTRACK FLAGS==================
-         * this one is used when you need to check tags path first  - Razao Social path
-         * second - Shipping Company
-         */
+mod tags{
+    use std::borrow::Cow;
 
-        (flags, backtrack)
-    }
+    use quick_xml::encoding::EncodingError;
+    use quick_xml::events::{BytesRef, BytesStart};
+    use quick_xml::Decoder;
+    use quick_xml::escape::resolve_predefined_entity;
 
-    pub fn update_flag(flags:&mut u8, flag:u8){
-        *flags ^= flag;
+    use crate::types::*;
+    use crate::constants::*;
+    use crate::diagnostic::{Code, Diagnostic};
+
+    /* keeps the elements from the root of the document down to the current
+     * one, so every text can be matched by where it is and not only by the
+     * name of the tag that holds it (xNome exists inside emit, dest and
+     * transporta, for instance)
+     */
+    #[derive(Debug,Default)]
+    pub struct ElementPath{
+        elements: Vec<String>,
+        text: String,
+        root: String,
+        latin1_fallback: bool,
     }
 
-    pub fn check_flag(flags:&u8, flag:u8) -> bool{
-        *flags&flag == flag
-    }
+    impl ElementPath{
+        pub fn open(&mut self, tag_name:TagName){
+            if self.root.is_empty() {
+                self.root = String::from_utf8_lossy(tag_name).to_string();
+            }
+            self.elements.push(String::from_utf8_lossy(tag_name).to_string());
+            self.text.clear();
+        }
 
-}
+        pub fn close(&mut self){
+            self.elements.pop();
+            self.text.clear();
+        }
 
-mod tags{
-    use std::collections::HashMap;
-    use quick_xml::events::BytesText;
-    use quick_xml::encoding::EncodingError;
+        pub fn take_text(&mut self) -> String{
+            let text = self.text.trim().to_string();
+            self.text.clear();
+            text
+        }
 
-    use crate::types::*;
-    use crate::constants::*;
-    use super::flags;
-
-    pub fn match_tag(tag_name:TagName, flags:&mut u8, backtrack:&mut u8) {
-        match tag_name{
-            DANFE_TAG => flags::update_flag(flags, DANFE_FLAG),
-            DANFE_TAG_SECOND => flags::update_flag(flags, DANFE_FLAG),
-            LOAD_CUBICAGE_TAG => flags::update_flag(flags, LOAD_CUBICAGE_FLAG),
-            RAZAO_SOCIAL_FIRST_TAG => flags::update_flag(backtrack, RAZAO_SOCIAL_BACKTRACK_FLAG),
-            SHIPPING_COMPANY_FIRST_TAG => flags::update_flag(backtrack, SHIPPING_COMPANY_BACKTRACK_FLAG),
-            X_NOME => {
-                if flags::check_flag(backtrack, RAZAO_SOCIAL_BACKTRACK_FLAG) {
-                    flags::update_flag(flags, RAZAO_SOCIAL_FLAG);
+        pub fn push_text(&mut self, text:&str){
+            self.text.push_str(text);
+        }
+
+        /* some ERPs write ISO-8859-1 while declaring UTF-8 (or nothing at all),
+         * instead of failing the whole file the raw bytes are read as latin-1,
+         * which maps every byte straight to the same code point
+         */
+        pub fn push_decoded(&mut self, decoded:Result<Cow<str>, EncodingError>, raw:&[u8]){
+            match decoded {
+                Ok(text) => self.push_text(&text),
+                Err(_) => {
+                    self.latin1_fallback = true;
+                    self.text.extend(raw.iter().map(|byte| *byte as char));
                 }
-                if flags::check_flag(backtrack, SHIPPING_COMPANY_BACKTRACK_FLAG) {
-                    flags::update_flag(flags, SHIPPING_COMPANY_FLAG);
+            }
+        }
+
+        pub fn used_latin1_fallback(&self) -> bool{
+            self.latin1_fallback
+        }
+
+        pub fn push_reference(&mut self, reference:&BytesRef) -> Result<(), ParseErrors>{
+            if let Some(character) = reference.resolve_char_ref()? {
+                self.text.push(character);
+                return Ok(());
+            }
+
+            let entity = reference.decode()?;
+            match resolve_predefined_entity(&entity) {
+                Some(value) => self.text.push_str(value),
+                None => self.text.push_str(format!("&{};", entity).as_str()),
+            }
+            Ok(())
+        }
+
+        pub fn root(&self) -> &str{
+            &self.root
+        }
+
+        // path starting at infNFe, protNFe, evento or retEvento, whatever wraps them (nfeProc, NFe, ...)
+        pub fn anchored(&self) -> Vec<&str>{
+            let anchors = [INF_NFE_TAG, PROT_NFE_TAG, EVENT_TAG, EVENT_RESULT_TAG];
+            match self.elements.iter().position(|element| anchors.contains(&element.as_str())) {
+                Some(start) => self.elements[start..].iter().map(String::as_str).collect(),
+                None => vec![]
+            }
+        }
+    }
+
+    pub fn match_start(path:&[&str], tag:&BytesStart, decoder:Decoder, nfe:&mut NFe) -> Result<(), ParseErrors>{
+        match path {
+            [INF_NFE_TAG] => {
+                if let Some(id) = tag.try_get_attribute(ID_ATTRIBUTE)? {
+                    nfe.id = id.decode_and_unescape_value(decoder)?.to_string();
                 }
             },
-            QUANTITY_TAG => flags::update_flag(flags, QUANTITY_FLAG),
-            ACCESS_KEY_TAG => flags::update_flag(flags, ACCESS_KEY_FLAG),
-            _ => (),
+            [INF_NFE_TAG, "entrega"] => nfe.delivery_place = Some(Party::default()),
+            [INF_NFE_TAG, ITEM_TAG] => {
+                // nItem is required, its position is the fallback when it's broken
+                let position = nfe.items.len() as u32 + 1;
+                let number = match tag.try_get_attribute(ITEM_NUMBER_ATTRIBUTE)? {
+                    Some(number) => number.decode_and_unescape_value(decoder)?.parse::<u32>().unwrap_or(position),
+                    None => position
+                };
+                nfe.items.push(Item{ number, ..Default::default() });
+            },
+            [INF_NFE_TAG, "transp", VOLUME_TAG] => nfe.volumes.push(Volume::default()),
+            [INF_NFE_TAG, "transp", "veicTransp"] => nfe.vehicle = Some(Vehicle::default()),
+            [INF_NFE_TAG, "transp", "reboque"] => nfe.trailers.push(Vehicle::default()),
+            _ => ()
         }
+        Ok(())
     }
 
-    pub fn match_text(flags:&u8, text:&BytesText, tmp_data:&mut HashMap<String,String>) -> Result<(), EncodingError>{
-        let text_data = text.decode()?.to_string();
-        if flags::check_flag(&flags, DANFE_FLAG) {
-            tmp_data.insert(String::from("danfe"), text_data.clone());
+    pub fn match_text(path:&[&str], text:String, nfe:&mut NFe, errors:&mut Vec<Diagnostic>){
+        match path {
+            [INF_NFE_TAG, "ide", "mod"] => nfe.identification.model = text,
+            [INF_NFE_TAG, "ide", "serie"] => nfe.identification.series = text,
+            [INF_NFE_TAG, "ide", "nNF"] => nfe.identification.number = text,
+            [INF_NFE_TAG, "ide", "dhEmi"] => nfe.identification.issued_at = text,
+            [INF_NFE_TAG, "emit", field] => match_party(field, text, &mut nfe.emitter),
+            [INF_NFE_TAG, "emit", "enderEmit", field] => match_address(field, text, &mut nfe.emitter.address),
+            [INF_NFE_TAG, "dest", field] => match_party(field, text, &mut nfe.recipient),
+            [INF_NFE_TAG, "dest", "enderDest", field] => match_address(field, text, &mut nfe.recipient.address),
+            [INF_NFE_TAG, "entrega", field] => {
+                if let Some(place) = nfe.delivery_place.as_mut() {
+                    match_party(field, text.clone(), place);
+                    match_address(field, text, &mut place.address);
+                }
+            },
+            [INF_NFE_TAG, ITEM_TAG, "prod", field] => {
+                if let Some(item) = nfe.items.last_mut() {
+                    match_item(field, text, item, errors);
+                }
+            },
+            [INF_NFE_TAG, "total", "ICMSTot", "vProd"] => nfe.totals.products_value = parse_value(&text, "products value", "vProd", errors),
+            [INF_NFE_TAG, "total", "ICMSTot", "vNF"] => nfe.totals.value = parse_value(&text, "total value", "vNF", errors),
+            [INF_NFE_TAG, "transp", "transporta", field] => match_party(field, text, &mut nfe.transporter),
+            [INF_NFE_TAG, "transp", VOLUME_TAG, "qVol"] => {
+                if let Some(volume) = nfe.volumes.last_mut() {
+                    volume.quantity = parse_value(&text, "quantity", "qVol", errors);
+                }
+            },
+            [INF_NFE_TAG, "transp", VOLUME_TAG, "esp"] => {
+                if let Some(volume) = nfe.volumes.last_mut() {
+                    volume.species = text;
+                }
+            },
+            [INF_NFE_TAG, "transp", VOLUME_TAG, "marca"] => {
+                if let Some(volume) = nfe.volumes.last_mut() {
+                    volume.brand = text;
+                }
+            },
+            [INF_NFE_TAG, "transp", VOLUME_TAG, "pesoL"] => {
+                if let Some(volume) = nfe.volumes.last_mut() {
+                    volume.net_weight = parse_value(&text, "net weight", "pesoL", errors);
+                }
+            },
+            [INF_NFE_TAG, "transp", VOLUME_TAG, "pesoB"] => {
+                if let Some(volume) = nfe.volumes.last_mut() {
+                    volume.gross_weight = parse_value(&text, "gross weight", "pesoB", errors);
+                }
+            },
+            [INF_NFE_TAG, "transp", "veicTransp", field] => {
+                if let Some(vehicle) = nfe.vehicle.as_mut() {
+                    match_vehicle(field, text, vehicle);
+                }
+            },
+            [INF_NFE_TAG, "transp", "reboque", field] => {
+                if let Some(trailer) = nfe.trailers.last_mut() {
+                    match_vehicle(field, text, trailer);
+                }
+            },
+            [INF_NFE_TAG, "cobr", "fat", "nFat"] => nfe.invoice_number = text,
+            [INF_NFE_TAG, "infAdic", "infCpl"] => nfe.additional_info = text,
+            [PROT_NFE_TAG, "infProt", "chNFe"] => nfe.protocol.key = text,
+            [PROT_NFE_TAG, "infProt", "nProt"] => nfe.protocol.number = text,
+            [PROT_NFE_TAG, "infProt", "cStat"] => nfe.protocol.status = text,
+            [PROT_NFE_TAG, "infProt", "dhRecbto"] => nfe.protocol.received_at = text,
+            _ => ()
         }
-        if flags::check_flag(&flags, RAZAO_SOCIAL_FLAG) {
-            tmp_data.insert(String::from("to"), text_data.clone());
+    }
+
+    pub fn match_event_text(path:&[&str], text:String, event:&mut NFeEvent){
+        match path {
+            [EVENT_TAG, "infEvento", "chNFe"] => event.key = text,
+            [EVENT_TAG, "infEvento", "tpEvento"] => event.event_type = text,
+            [EVENT_TAG, "infEvento", "nSeqEvento"] => event.sequence = text,
+            [EVENT_TAG, "infEvento", "dhEvento"] => event.issued_at = text,
+            [EVENT_TAG, "infEvento", "detEvento", "descEvento"] => event.description = text,
+            [EVENT_TAG, "infEvento", "detEvento", "xCorrecao"] => event.correction = text,
+            [EVENT_TAG, "infEvento", "detEvento", "xJust"] => event.justification = text,
+            [EVENT_RESULT_TAG, "infEvento", "cStat"] => event.status = text,
+            [EVENT_RESULT_TAG, "infEvento", "chNFe"] if event.key.is_empty() => event.key = text,
+            [EVENT_RESULT_TAG, "infEvento", "tpEvento"] if event.event_type.is_empty() => event.event_type = text,
+            _ => ()
         }
-        if flags::check_flag(&flags, SHIPPING_COMPANY_FLAG) {
-            tmp_data.insert(String::from("by"), text_data.clone());
+    }
+
+    fn match_party(field:&str, text:String, party:&mut Party){
+        match field {
+            "xNome" => party.name = text,
+            "CNPJ" => party.cnpj = text,
+            "CPF" => party.cpf = text,
+            "IE" => party.ie = text,
+            _ => ()
         }
-        if flags::check_flag(&flags, LOAD_CUBICAGE_FLAG) {
-            tmp_data.insert(String::from("info"), text_data.clone());
+    }
+
+    fn match_item(field:&str, text:String, item:&mut Item, errors:&mut Vec<Diagnostic>){
+        match field {
+            "cProd" => item.code = text,
+            "xProd" => item.description = text,
+            "NCM" => item.ncm = text,
+            "CFOP" => item.cfop = text,
+            "qCom" => item.quantity = parse_value(&text, "item quantity", "qCom", errors),
+            "uCom" => item.unit = text,
+            "vProd" => item.value = parse_value(&text, "item value", "vProd", errors),
+            "qTrib" => item.taxable_quantity = parse_value(&text, "item taxable quantity", "qTrib", errors),
+            "uTrib" => item.taxable_unit = text,
+            _ => ()
         }
-        if flags::check_flag(&flags, QUANTITY_FLAG) {
-            tmp_data.insert(String::from("quantity"), text_data.clone());
+    }
+
+    fn match_vehicle(field:&str, text:String, vehicle:&mut Vehicle){
+        match field {
+            "placa" => vehicle.plate = text,
+            "UF" => vehicle.state = text,
+            "RNTC" => vehicle.rntc = text,
+            _ => ()
         }
-        if flags::check_flag(&flags, ACCESS_KEY_FLAG) {
-            tmp_data.insert(String::from("access_key"), text_data.clone());
+    }
+
+    fn match_address(field:&str, text:String, address:&mut Address){
+        match field {
+            "xLgr" => address.street = text,
+            "nro" => address.number = text,
+            "xCpl" => address.complement = text,
+            "xBairro" => address.district = text,
+            "cMun" => address.city_code = text,
+            "xMun" => address.city = text,
+            "UF" => address.state = text,
+            "CEP" => address.zip_code = text,
+            _ => ()
         }
+    }
 
-        Ok(())
+    fn parse_value<T: std::str::FromStr>(text:&str, name:&str, tag:&str, errors:&mut Vec<Diagnostic>) -> Option<T>
+    where T::Err: std::fmt::Debug {
+        match text.parse::<T>() {
+            Ok(value) => Some(value),
+            Err(error) => {
+                errors.push(
+                    Diagnostic::error(Code::InvalidValue, format!("Failed on parse {} : {:?} ", name, error))
+                        .with_field(tag)
+                );
+                None
+            }
+        }
     }
 }
 
@@ -90,8 +272,8 @@ mod tags{
 pub mod text{
     use crate::types::LoadNumber;
 
-    pub fn generate_email_text(loads:&Vec<LoadNumber>) -> String{
-        if loads.len() <= 0{
+    pub fn generate_email_text(loads:&[LoadNumber]) -> String{
+        if loads.is_empty(){
             return String::from("");
         }
 
@@ -111,288 +293,909 @@ pub mod text{
         
         text.push_str("att.");
         
-        return text;
+        text
+    }
+
+    // abc-1234, ABC 1234 and ABC1234 are the same plate
+    pub fn normalise_plate(plate:&str) -> String {
+        plate.chars()
+            .filter(|character| character.is_ascii_alphanumeric())
+            .map(|character| character.to_ascii_uppercase())
+            .collect()
+    }
+
+    // "Transportes São João Ltda." and "TRANSPORTES SAO JOAO LTDA" are the same carrier
+    pub fn normalise_name(name:&str) -> String {
+        name.to_lowercase()
+            .chars()
+            .map(without_accent)
+            .map(|character| if character.is_alphanumeric() { character } else { ' ' })
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    fn without_accent(character:char) -> char {
+        match character {
+            'á' | 'à' | 'â' | 'ã' | 'ä' => 'a',
+            'é' | 'è' | 'ê' | 'ë' => 'e',
+            'í' | 'ì' | 'î' | 'ï' => 'i',
+            'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+            'ú' | 'ù' | 'û' | 'ü' => 'u',
+            'ç' => 'c',
+            'ñ' => 'n',
+            _ => character
+        }
     }
 }
 
 
-pub mod parsing{
+pub mod danfe{
+    use regex::Captures;
+
+    use crate::pattern;
+    use crate::types::{DANFE, DocumentNumber};
+
+    pub fn format_danfe(danfe_pattern:&str, document:&DocumentNumber) -> DANFE{
+        pattern::danfe::placeholder().replace_all(danfe_pattern, |captures:&Captures| {
+            let value = match &captures[1] {
+                "series" => document.series.to_string(),
+                _ => document.number.to_string()
+            };
+            let width = captures.get(2).and_then(|width| width.as_str().parse::<usize>().ok()).unwrap_or(0);
+            format!("{:0>width$}", value, width = width)
+        }).to_string()
+    }
+}
+
+
+pub mod extraction{
     use std::collections::HashMap;
-    use std::path::PathBuf;
 
-    use quick_xml::events::Event;
-    use quick_xml::Reader;
+    use regex::Regex;
 
+    use crate::config::{ExtractionRules, Rule, RuleSet};
+    use crate::diagnostic::{Code, Diagnostic};
     use crate::pattern;
     use crate::types::*;
 
-    use super::*;
+    #[derive(Debug,PartialEq)]
+    pub struct Found {
+        pub rule: String,
+        pub value: String,
+        pub unit: String,
+    }
 
-    pub fn parse_email(email_text:&String) -> Result<EmailData, ParseErrors>{
-        let pattern_email = pattern::text::email_text();
-        let mut data = HashMap::new();
-
-        for (_, [load_number, license_plate, price]) in pattern_email.captures_iter(email_text.to_lowercase().as_str()).map(|cap| cap.extract()){
-            let load_number_parsed = load_number.parse::<LoadNumber>()?;
-            data.insert(
-                load_number_parsed,
-                EmailLoadData{
-                    price: price.replace(".","").replace(",",".").parse::<Price>()?,
-                    license_plate: license_plate.to_string()
-                }
-            );
+    #[derive(Debug)]
+    pub struct CompiledRule {
+        pub name: String,
+        regex: Regex,
+    }
+
+    #[derive(Debug,Default)]
+    struct CompiledSet {
+        load: Vec<CompiledRule>,
+        cubicage: Vec<CompiledRule>,
+    }
+
+    /* the rules from the config compiled a single time, a rule that doesn't
+     * compile is reported and left out
+     */
+    #[derive(Debug,Default)]
+    pub struct CompiledRules {
+        default: CompiledSet,
+        emitters: HashMap<String, CompiledSet>,
+    }
+
+    impl CompiledRules {
+        pub fn new(rules:&ExtractionRules) -> (Self, Vec<Diagnostic>) {
+            let mut errors = Vec::new();
+            let compiled = CompiledRules {
+                default: compile_set(&rules.default, &mut errors),
+                emitters: rules.emitters
+                    .iter()
+                    .map(|(emitter, set)| (emitter.clone(), compile_set(set, &mut errors)))
+                    .collect(),
+            };
+            (compiled, errors)
+        }
+
+        // same order as the config: the emitter rules first, the default ones after
+        pub fn load_rules(&self, emitter:&str) -> Vec<&CompiledRule> {
+            self.rules_for(emitter, |set| &set.load)
+        }
+
+        pub fn cubicage_rules(&self, emitter:&str) -> Vec<&CompiledRule> {
+            self.rules_for(emitter, |set| &set.cubicage)
+        }
+
+        fn rules_for<'a>(&'a self, emitter:&str, field:fn(&CompiledSet) -> &Vec<CompiledRule>) -> Vec<&'a CompiledRule> {
+            let emitter_rules = self.emitters.get(emitter).map(field).into_iter().flatten();
+            emitter_rules.chain(field(&self.default)).collect()
+        }
+    }
+
+    fn compile_set(set:&RuleSet, errors:&mut Vec<Diagnostic>) -> CompiledSet {
+        CompiledSet {
+            load: compile(&set.load, errors),
+            cubicage: compile(&set.cubicage, errors),
+        }
+    }
+
+    fn compile(rules:&[Rule], errors:&mut Vec<Diagnostic>) -> Vec<CompiledRule> {
+        rules.iter().filter_map(|rule| match pattern::xml::rule(&rule.pattern) {
+            Ok(regex) => Some(CompiledRule{ name: rule.name.clone(), regex }),
+            Err(error) => {
+                errors.push(Diagnostic::error(Code::InvalidRule, format!("Invalid pattern on rule {}: {}", rule.name, error)));
+                None
+            }
+        }).collect()
+    }
+
+    // the first rule that matches wins, the order in the config matters
+    pub fn find(text:&str, rules:&[&CompiledRule]) -> Option<Found> {
+        for rule in rules {
+            let Some(captures) = rule.regex.captures(text) else { continue };
+            let Some(value) = captures.name("value").or_else(|| captures.get(1)) else { continue };
+
+            return Some(Found {
+                rule: rule.name.clone(),
+                value: value.as_str().to_string(),
+                unit: captures.name("unit").map(|unit| unit.as_str().to_string()).unwrap_or_default(),
+            });
+        }
+        None
+    }
+
+    /* 3,431 | 3.4 | 1.234,5 | 1,234.5
+     * when both separators show up the last one is the decimal one, a
+     * separator repeated more than once can only be grouping thousands
+     */
+    pub fn normalise_decimal(value:&str) -> String {
+        let value = value.trim();
+        let decimal = match (value.rfind(','), value.rfind('.')) {
+            (Some(comma), Some(dot)) => Some(if comma > dot { ',' } else { '.' }),
+            (Some(_), None) => Some(','),
+            (None, Some(_)) => Some('.'),
+            (None, None) => None,
+        };
+
+        match decimal {
+            Some(separator) if value.matches(separator).count() == 1 => value
+                .chars()
+                .filter(|character| character.is_ascii_digit() || *character == separator)
+                .map(|character| if character == separator { '.' } else { character })
+                .collect(),
+            _ => normalise_integer(value)
         }
+    }
 
+    pub fn normalise_integer(value:&str) -> String {
+        value.chars().filter(|character| character.is_ascii_digit()).collect()
+    }
 
-        Ok(data)
+    // no unit means the value is already in m3
+    pub fn cubic_meters_per(unit:&str) -> Cubicage {
+        match unit.to_lowercase().replace('³', "3").as_str() {
+            "dm3" => 0.001,
+            "cm3" => 0.000001,
+            _ => 1.0
+        }
     }
+}
 
-    pub fn parse_file(file:&PathBuf) -> Result<(Data, Vec<Error>), ParseErrors> {
-        let mut reader = Reader::from_file(file)?;
-        reader.config_mut().trim_text(true);
+pub mod parsing{
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, BufRead};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
 
-        let (mut flags, mut backtrack) = flags::generate_flags();
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use rayon::prelude::*;
+
+    use crate::cache::{self, content_hash, CachedDocument, ParseCache, SeenFile};
+    use crate::classify::{classify_reader, Classification};
+    use crate::config::Config;
+    use crate::constants::EVENT_ROOT_TAGS;
+    use crate::diagnostic::{self, Code, Diagnostic};
+    use crate::files::{read_file, read_files};
+    use super::extraction::CompiledRules;
+    use crate::key::{ChaveAcesso, NFE_MODEL};
+    use crate::email;
+    use crate::signature::{self, SignatureStatus};
+    use crate::tax_id;
+    use crate::types::*;
+
+    use super::*;
+
+    /* lines that mention a load but couldn't be read come back as diagnostics,
+     * a whole message (.eml) also brings its sender, date and subject
+     */
+    pub fn parse_email(email_text:&str) -> ParsedEmail{
+        email::parse(email_text)
+    }
+
+    // what an HTTP body or a saved .eml gives, in any charset
+    pub fn parse_email_bytes(content:&[u8]) -> ParsedEmail{
+        email::parse_bytes(content)
+    }
+
+    // file may also be a member of an archive (notes.zip!/2024/file.xml)
+    pub fn parse_document(file:&Path) -> Result<(Document, Vec<Diagnostic>), ParseErrors> {
+        parse_document_reader(&read_file(file)?[..])
+    }
+
+    // the whole content is kept, the signature is checked over the raw bytes
+    pub fn parse_document_reader<R: BufRead>(mut reader:R) -> Result<(Document, Vec<Diagnostic>), ParseErrors> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+
+        let (mut document, errors) = read_document(Reader::from_reader(&content[..]))?;
+        if let Document::NFe(nfe) = &mut document {
+            nfe.signature = signature::verify(&content, &nfe.id);
+        }
+        Ok((document, errors))
+    }
+
+    pub fn parse_nfe(file:&Path) -> Result<(NFe, Vec<Diagnostic>), ParseErrors> {
+        Ok(only_nfe(parse_document(file)?))
+    }
+
+    pub fn parse_nfe_reader<R: BufRead>(reader:R) -> Result<(NFe, Vec<Diagnostic>), ParseErrors> {
+        Ok(only_nfe(parse_document_reader(reader)?))
+    }
+
+    fn only_nfe((document, mut errors):(Document, Vec<Diagnostic>)) -> (NFe, Vec<Diagnostic>) {
+        match document {
+            Document::NFe(nfe) => (*nfe, errors),
+            Document::Event(event) => {
+                errors.push(
+                    Diagnostic::error(Code::UnexpectedEvent, format!("Expected a NF-e, found the event {} for {}", event.event_type, event.key))
+                        .with_key(&event.key)
+                );
+                (NFe::default(), errors)
+            }
+        }
+    }
 
-        let mut tmp_data = HashMap::new();
+    fn read_document<R: BufRead>(mut reader:Reader<R>) -> Result<(Document, Vec<Diagnostic>), ParseErrors> {
+        let mut path = tags::ElementPath::default();
+        let mut nfe = NFe::default();
+        let mut event = NFeEvent::default();
         let mut buffer = Vec::new();
         let mut errors = Vec::new();
         
         loop{
             match reader.read_event_into(&mut buffer){
                 Err(error) => {
-                    errors.push(String::from(format!("Failed on read data from xml: {:?} at position {}", error, reader.error_position())));
+                    errors.push(
+                        Diagnostic::error(Code::XmlRead, format!("Failed on read data from xml: {:?} at position {}", error, reader.error_position()))
+                            .at(reader.error_position())
+                    );
                     break;
                 },
-                Ok(Event::Start(tag)) => tags::match_tag(tag.name().as_ref(), &mut flags, &mut backtrack),
-                Ok(Event::End(tag)) => tags::match_tag(tag.name().as_ref(), &mut flags, &mut backtrack),
-                Ok(Event::Text(text)) => tags::match_text(&flags, &text, &mut tmp_data)?,
+                Ok(Event::Start(tag)) => {
+                    path.open(tag.local_name().as_ref());
+                    tags::match_start(&path.anchored(), &tag, reader.decoder(), &mut nfe)?;
+                },
+                Ok(Event::End(_)) => {
+                    let text = path.take_text();
+                    let current_path = path.anchored();
+                    let before = errors.len();
+                    tags::match_event_text(&current_path, text.clone(), &mut event);
+                    tags::match_text(&current_path, text, &mut nfe, &mut errors);
+                    diagnostic::fill_position(&mut errors[before..], reader.buffer_position());
+                    path.close();
+                },
+                Ok(Event::Text(text)) => path.push_decoded(text.decode(), &text),
+                Ok(Event::CData(text)) => path.push_decoded(text.decode(), &text),
+                Ok(Event::GeneralRef(reference)) => path.push_reference(&reference)?,
                 Ok(Event::Eof) => break,
                 _ => ()
             }
             buffer.clear();
         }
-        
-        let mut load_number : LoadNumber = 0;
-        let mut cubicage: Cubicage = 0.0;
-        let mut quantity: Quantity = 0;
-
-        match tmp_data.get("info"){
-            Some(value) => {
 
-                let pattern_load = pattern::xml::load();
-                let pattern_cubicage = pattern::xml::cubicage();
+        if path.used_latin1_fallback() {
+            errors.push(Diagnostic::warning(Code::Encoding, format!("Text doesn't match the declared encoding {}, read as ISO-8859-1", reader.decoder().encoding().name())));
+        }
 
-                let info = value.to_lowercase();
-                match pattern_load.captures(&info){
-                    Some(matched_value) => {
-                    
-                        match matched_value.get(1) {
-                            Some(value) => {
+        if EVENT_ROOT_TAGS.contains(&path.root()) {
+            diagnostic::fill_key(&mut errors, &event.key);
+            return Ok((Document::Event(event), errors));
+        }
 
-                                match value.as_str().parse::<LoadNumber>(){
-                                    Ok(parsed_value) => {
-                                        load_number = parsed_value;
-                                    }
-                                    Err(error) => {
-                                        errors.push(String::from(format!("Failed on parse load number: {:?}",error)));
-                                    }
-                                }
+        diagnostic::fill_key(&mut errors, &nfe.access_key());
+        Ok((Document::NFe(Box::new(nfe)), errors))
+    }
 
+    pub fn parse_file(file:&Path, config:&Config) -> Result<(Data, Vec<Diagnostic>), ParseErrors> {
+        let (nfe, errors) = parse_nfe(file)?;
+        let (data, mut errors) = into_data(&nfe, errors, config);
+        diagnostic::fill_file(&mut errors, file);
+        Ok((data, errors))
+    }
 
-                            }
-                            None => {
-                                errors.push(String::from("Couldnt get the match for load"));
-                            }
-                        }
+    pub fn parse_reader<R: BufRead>(reader:R, config:&Config) -> Result<(Data, Vec<Diagnostic>), ParseErrors> {
+        let (nfe, errors) = parse_nfe_reader(reader)?;
+        Ok(into_data(&nfe, errors, config))
+    }
 
-                    }
-                    None => {
-                        errors.push(String::from("No matches for load"));
-                    }
-                }
+    pub fn parse_bytes(bytes:&[u8], config:&Config) -> Result<(Data, Vec<Diagnostic>), ParseErrors> {
+        parse_reader(bytes, config)
+    }
 
-                match pattern_cubicage.captures(&info){
-                    Some(matched_value) => {
-                    
-                        match matched_value.get(1) {
-                            Some(value) => {
+    fn into_data(nfe:&NFe, mut errors:Vec<Diagnostic>, config:&Config) -> (Data, Vec<Diagnostic>) {
+        let (data, data_errors) = extract_data(nfe, config);
+        errors.extend(data_errors);
+        (data, errors)
+    }
 
-                                match value.as_str().replace(",",".").parse::<Cubicage>(){
-                                    Ok(parsed_value) => {
-                                        cubicage = parsed_value;
-                                    }
-                                    Err(error) => {
-                                        errors.push(String::from(format!("Failed on parse cubicage: {:?}",error)));
-                                    }
-                                }
+    // compiles the extraction rules for a single note, batches compile them once and use extract_data_with
+    pub fn extract_data(nfe:&NFe, config:&Config) -> (Data, Vec<Diagnostic>) {
+        let (rules, mut errors) = CompiledRules::new(&config.extraction);
+        let (data, data_errors) = extract_data_with(nfe, config, &rules);
+        errors.extend(data_errors);
+        (data, errors)
+    }
 
+    pub fn extract_data_with(nfe:&NFe, config:&Config, rules:&CompiledRules) -> (Data, Vec<Diagnostic>) {
+        let mut errors = Vec::new();
 
-                            }
-                            None => {
-                                errors.push(String::from("Couldnt get the match for cubicage"));
-                            }
-                        }
+        let mut load_number : LoadNumber = 0;
+        let mut cubicage: Cubicage = 0.0;
+        let mut quantity: Quantity = 0;
 
+        let mut load_rule = None;
+        let mut cubicage_rule = None;
+        let mut cubicage_missing = false;
+
+        if nfe.additional_info.is_empty() {
+            errors.push(Diagnostic::error(Code::MissingInfo, String::from("No Data for Info")).with_field("infCpl"));
+        } else {
+            let info = nfe.additional_info.to_lowercase();
+            let emitter = &nfe.emitter.cnpj;
+
+            match extraction::find(&info, &rules.load_rules(emitter)){
+                Some(found) => {
+                    match extraction::normalise_integer(&found.value).parse::<LoadNumber>(){
+                        Ok(parsed_value) => {
+                            load_number = parsed_value;
+                            load_rule = Some(found.rule);
+                        },
+                        Err(error) => errors.push(Diagnostic::error(Code::InvalidLoad, format!("Failed on parse load number: {:?}",error)).with_field("infCpl"))
                     }
-                    None => {
-                        errors.push(String::from("No matches for cubicage"));
+                }
+                None => errors.push(Diagnostic::error(Code::MissingLoad, String::from("No matches for load")).with_field("infCpl"))
+            }
+
+            match extraction::find(&info, &rules.cubicage_rules(emitter)){
+                Some(found) => {
+                    match extraction::normalise_decimal(&found.value).parse::<Cubicage>(){
+                        Ok(parsed_value) => {
+                            cubicage = parsed_value * extraction::cubic_meters_per(&found.unit);
+                            cubicage_rule = Some(found.rule);
+                        },
+                        Err(error) => errors.push(Diagnostic::error(Code::InvalidCubicage, format!("Failed on parse cubicage: {:?}",error)).with_field("infCpl"))
                     }
                 }
-            },
-            None => {
-                errors.push(String::from("No Data for Info"));
+                None => cubicage_missing = true
             }
         }
 
+        let net_weight : Weight = nfe.volumes.iter().filter_map(|volume| volume.net_weight).sum();
+        let mut gross_weight : Weight = nfe.volumes.iter().filter_map(|volume| volume.gross_weight).sum();
+        let estimate = config.catalog.estimate(&nfe.items);
 
-        match tmp_data.get("quantity") {
-            Some(value) =>  {
-                
-                match value.parse::<Quantity>() {
-                    Ok(parsed_value) => {
-                        quantity = parsed_value;
-                    },
-                    Err(error) => {
-                        errors.push(String::from(format!("Failed on parse quantity : {:?} ", error)));
-                    },
+        let cubicage_source = match (&cubicage_rule, estimate) {
+            (Some(_), estimate) => {
+                if let Some(estimate) = estimate {
+                    errors.push(
+                        Diagnostic::info(Code::DeclaredCubicage, format!("Cubicage declared as {:.3} m3, the product catalog estimates {:.3} m3", cubicage, estimate.cubicage))
+                            .with_field("infCpl")
+                    );
+                }
+                CubicageSource::Declared
+            },
+            (None, Some(estimate)) => {
+                cubicage = estimate.cubicage;
+                // kg per unit is what the load weighs, so it only stands in for pesoB
+                if gross_weight == 0.0 {
+                    gross_weight = estimate.weight;
                 }
 
-
+                let missing = match estimate.missing.is_empty() {
+                    true => String::new(),
+                    false => format!(", products not in the catalog: {}", estimate.missing.join(", ")),
+                };
+                errors.push(
+                    Diagnostic::warning(Code::EstimatedCubicage, format!("Cubicage estimated from the product catalog as {:.3} m3{}", cubicage, missing))
+                        .with_field("det")
+                );
+                CubicageSource::Estimated
             },
-            None => {
-                errors.push(String::from("No Quantity from parsed data!"));
+            (None, None) => {
+                if cubicage_missing {
+                    errors.push(Diagnostic::error(Code::MissingCubicage, String::from("No matches for cubicage")).with_field("infCpl"));
+                }
+                CubicageSource::Missing
             }
+        };
 
+        let quantities = nfe.volumes.iter().filter_map(|volume| volume.quantity).collect::<Vec<_>>();
+        if quantities.is_empty() {
+            errors.push(Diagnostic::error(Code::MissingQuantity, String::from("No Quantity from parsed data!")).with_field("qVol"));
+        } else {
+            quantity = quantities.iter().sum();
         }
 
 
-        let danfe = match tmp_data.get("danfe") {
-            Some(value) => {
-                let mut danfe = value.clone();
-                fix_danfe(&mut danfe);
-                danfe
-            },
-            None => {
-                errors.push(String::from("No DANFE"));
-                String::from("")
-            }
+        let key = nfe.access_key();
+        let access_key = if key.is_empty() {
+            errors.push(Diagnostic::error(Code::MissingKey, String::from("No NF-e Key")).with_field("chNFe"));
+            None
+        } else {
+            validate_access_key(&key, nfe, &mut errors)
         };
-        
-        let to = match tmp_data.get("to") {
-            Some(value) => {
-                value.clone()
-            },
+
+        let (danfe, document) = match document_number(nfe, access_key.as_ref()) {
+            Some(document) => (danfe::format_danfe(config.danfe.pattern_for(&nfe.emitter.cnpj), &document), document),
             None => {
-                errors.push(String::from("No client data"));
-                String::from("")
+                errors.push(Diagnostic::error(Code::MissingDanfe, String::from("No DANFE")).with_field("nNF"));
+                (String::from(""), DocumentNumber::default())
             }
         };
-        
-        let by = match tmp_data.get("by") {
-            Some(value) => {
-                value.clone()
+
+        if nfe.recipient.name.is_empty() {
+            errors.push(Diagnostic::error(Code::MissingClient, String::from("No client data")).with_field("dest"));
+        }
+
+        if nfe.transporter.name.is_empty() {
+            errors.push(Diagnostic::error(Code::MissingCarrier, String::from("No carrier data")).with_field("transporta"));
+        }
+
+        validate_party_ids("emit", &nfe.emitter, &mut errors);
+        validate_party_ids("dest", &nfe.recipient, &mut errors);
+        validate_party_ids("transporta", &nfe.transporter, &mut errors);
+
+        match &nfe.signature {
+            SignatureStatus::NotChecked | SignatureStatus::Valid => (),
+            SignatureStatus::Missing => errors.push(Diagnostic::warning(Code::UnsignedNFe, String::from("NF-e has no digital signature")).with_field("Signature")),
+            status => errors.push(Diagnostic::warning(Code::InvalidSignature, format!("NF-e signature is not valid: {}", status)).with_field("Signature")),
+        }
+
+        // which rule read what, so a wrong load number can be traced to the config
+        let matched : Vec<String> = [(&load_rule, "load number"), (&cubicage_rule, "cubicage")]
+            .iter()
+            .filter_map(|(rule, value)| rule.as_ref().map(|rule| format!("{} by rule {}", value, rule)))
+            .collect();
+        if !matched.is_empty() {
+            errors.push(Diagnostic::info(Code::ExtractionRule, format!("Read from infCpl: {}", matched.join(", "))).with_field("infCpl"));
+        }
+
+        diagnostic::fill_key(&mut errors, &key);
+        if load_rule.is_some() {
+            diagnostic::fill_load(&mut errors, load_number);
+        }
+
+
+        (
+            Data {
+                danfe,
+                document,
+                to: nfe.recipient.name.clone(),
+                recipient: nfe.recipient.clone(),
+                delivery_place: nfe.delivery_place.clone(),
+                by: nfe.transporter.name.clone(),
+                carrier_key: nfe.transporter.carrier_key(),
+                quantity,
+                load_number,
+                load_rule,
+                cubicage,
+                cubicage_rule,
+                cubicage_source,
+                key,
+                access_key,
+                has_protocol: !nfe.protocol.key.is_empty(),
+                signature: nfe.signature.clone(),
+                value: nfe.totals.value.unwrap_or(0.0),
+                net_weight,
+                gross_weight,
+                volumes: nfe.volumes.clone(),
+                vehicle: nfe.vehicle.clone(),
+                trailers: nfe.trailers.clone(),
+                items: nfe.items.clone(),
+                corrections: vec![],
             },
-            None => {
-                errors.push(String::from("No carrier data"));
-                String::from("")
+            errors
+        )
+    }
+
+    // series and nNF from ide, the access key is the fallback
+    fn document_number(nfe:&NFe, access_key:Option<&ChaveAcesso>) -> Option<DocumentNumber> {
+        let series = nfe.identification.series.parse::<Series>();
+        let number = nfe.identification.number.parse::<NFNumber>();
+        if let (Ok(series), Ok(number)) = (series, number) {
+            return Some(DocumentNumber{series, number});
+        }
+
+        let access_key = access_key?;
+        Some(DocumentNumber{
+            series: access_key.series.parse::<Series>().ok()?,
+            number: access_key.number.parse::<NFNumber>().ok()?,
+        })
+    }
+
+    // SEFAZ rejects a CT-e whose parties carry an invalid CNPJ/CPF, absent ones are reported elsewhere
+    fn validate_party_ids(tag:&str, party:&Party, errors:&mut Vec<Diagnostic>) {
+        let results = [
+            ("CNPJ", &party.cnpj, (!party.cnpj.is_empty()).then(|| tax_id::validate_cnpj(&party.cnpj))),
+            ("CPF", &party.cpf, (!party.cpf.is_empty()).then(|| tax_id::validate_cpf(&party.cpf))),
+        ];
+
+        for (name, id, result) in results {
+            if let Some(Err(error)) = result {
+                errors.push(
+                    Diagnostic::error(Code::InvalidTaxId, format!("Invalid {} {} in {}: {}", name, id, tag, error))
+                        .with_field(&format!("{}/{}", tag, name))
+                );
+            }
+        }
+    }
+
+    fn validate_access_key(key:&str, nfe:&NFe, errors:&mut Vec<Diagnostic>) -> Option<ChaveAcesso> {
+        let access_key = match ChaveAcesso::parse(key) {
+            Ok(access_key) => access_key,
+            Err(error) => {
+                errors.push(Diagnostic::error(Code::InvalidKey, format!("Invalid NF-e Key {}: {}", key, error)).with_field("chNFe"));
+                return None;
             }
         };
+
+        if !access_key.is_nfe() {
+            errors.push(Diagnostic::error(Code::KeyModelMismatch, format!("NF-e Key {} has model {} instead of {}", key, access_key.model, NFE_MODEL)).with_field("chNFe"));
+        }
+
+        if !nfe.identification.number.is_empty() && !access_key.matches_number(&nfe.identification.number) {
+            errors.push(Diagnostic::error(Code::KeyNumberMismatch, format!("NF-e Key {} doesn't match the number {}", key, nfe.identification.number)).with_field("nNF"));
+        }
+
+        Some(access_key)
+    }
+
+    enum FileOutcome {
+        Skipped(Classification),
+        Event(NFeEvent, Vec<Diagnostic>),
+        NFe(Box<Data>, Vec<Diagnostic>),
+    }
+
+    /* every file succeeds or fails on its own, a failed one is reported and
+     * left out, and the load it belongs to (when that could still be read)
+     * is flagged as incomplete
+     */
+    pub fn parse_multiple(files:&[PathBuf], config:&Config) -> ParsedFiles{
+        parse_files(files, config, None)
+    }
+
+    /* files whose content is already in the cache aren't parsed again. The
+     * cache is only locked to look the files up and to record the run, so
+     * other runs can use it while this one parses. Saving and clearing it is
+     * up to the caller
+     */
+    pub fn parse_multiple_cached(files:&[PathBuf], config:&Config, cache:&Mutex<ParseCache>) -> ParsedFiles{
+        parse_files(files, config, Some(cache))
+    }
+
+    fn parse_files(files:&[PathBuf], config:&Config, cache:Option<&Mutex<ParseCache>>) -> ParsedFiles{
         
-        let key = match tmp_data.get("access_key") {
-            Some(value) => {
-                value.clone()
+        let mut parsed = ParsedFiles::default();
+        let mut events = Vec::new();
+
+        let (rules, rule_errors) = CompiledRules::new(&config.extraction);
+        parsed.errors.extend(rule_errors);
+
+        let contents = read_files(files);
+        let hashes : Vec<Option<String>> = match cache {
+            Some(_) => contents.par_iter().map(|content| content.as_ref().ok().map(|content| content_hash(content))).collect(),
+            None => vec![None; files.len()],
+        };
+        let hits : Vec<Option<CachedDocument>> = match cache {
+            Some(cache) => {
+                let cache = cache::lock(cache);
+                hashes.iter().map(|hash| hash.as_ref().and_then(|hash| cache.get(hash)).map(|entry| entry.document.clone())).collect()
             },
+            None => vec![None; files.len()],
+        };
+
+        // files are read across the thread pool, collect keeps them in the given order
+        let results : Vec<_> = files
+            .par_iter()
+            .zip(contents)
+            .zip(hits)
+            .map(|((file, content), hit)| read_one(file, content, hit, cache.is_some(), config, &rules))
+            .collect();
+
+        let mut seen = Vec::new();
+        let mut outcomes = Vec::with_capacity(results.len());
+        for ((file, hash), result) in files.iter().zip(hashes).zip(results) {
+            let outcome = result.map(|(outcome, fresh)| {
+                if let Some(hash) = hash {
+                    seen.push(SeenFile{ hash, file: file.display().to_string(), fresh });
+                }
+                outcome
+            });
+            outcomes.push(outcome);
+        }
+        if let Some(cache) = cache {
+            cache::lock(cache).record_run(seen);
+        }
+
+        let kept = kept_versions(&outcomes);
+
+        for (index, (file, outcome)) in files.iter().zip(outcomes).enumerate(){
+            match outcome {
+                Err(error) => fail_file(&mut parsed, file, error.to_string(), None, None),
+                Ok(FileOutcome::Skipped(classification)) => parsed.errors.push(
+                    Diagnostic::info(Code::SkippedDocument, format!("{} skipped: {}", file.display(), classification))
+                        .with_file(file)
+                ),
+                Ok(FileOutcome::Event(event, errors)) => match broken_xml(&errors) {
+                    Some(broken) => fail_file(&mut parsed, file, broken.message.clone(), broken.position, None),
+                    None => {
+                        parsed.errors.extend(errors);
+                        events.push(event);
+                    }
+                },
+                Ok(FileOutcome::NFe(data, errors)) => match broken_xml(&errors) {
+                    Some(broken) => {
+                        let load_number = data.load_rule.as_ref().map(|_| data.load_number);
+                        fail_file(&mut parsed, file, broken.message.clone(), broken.position, load_number);
+                    },
+                    None => match kept.get(&data.key) {
+                        Some(kept_index) if *kept_index != index => parsed.errors.push(
+                            Diagnostic::warning(Code::DuplicateNFe, format!("{} duplicates {}", file.display(), files[*kept_index].display()))
+                                .with_file(file)
+                                .with_key(&data.key)
+                                .with_load(data.load_number)
+                        ),
+                        _ => {
+                            parsed.errors.extend(errors);
+                            parsed.data.entry(data.load_number).or_default().push(*data);
+                        }
+                    }
+                },
+            }
+        }
+
+        apply_events(&mut parsed, &events);
+        parsed.incomplete.sort();
+        parsed.incomplete.dedup();
+
+        parsed
+    }
+
+    // the document read fresh is handed back too when it's going into the cache
+    fn read_one(file:&Path, content:io::Result<Vec<u8>>, hit:Option<CachedDocument>, cached:bool, config:&Config, rules:&CompiledRules) -> Result<(FileOutcome, Option<CachedDocument>), ParseErrors> {
+        let content = content?;
+
+        let (document, fresh) = match hit {
+            Some(document) => (document, None),
             None => {
-                errors.push(String::from("No NF-e Key"));
-                String::from("")
+                let document = read_content(&content)?;
+                let fresh = cached.then(|| document.clone());
+                (document, fresh)
             }
         };
 
+        Ok((outcome_of(file, document, config, rules), fresh))
+    }
 
-        Ok((
-            Data {
-                danfe: danfe,
-                to: to,
-                by: by,
-                quantity: quantity,
-                load_number: load_number,
-                cubicage: cubicage,
-                key: key
+    // everything that doesn't depend on the config, which is what goes into the cache
+    fn read_content(content:&[u8]) -> Result<CachedDocument, ParseErrors> {
+        let classification = classify_reader(content)?;
+        if !classification.kind.is_parsed() {
+            return Ok(CachedDocument::Skipped(classification));
+        }
+
+        let (document, errors) = parse_document_reader(content)?;
+        Ok(CachedDocument::Parsed(document, errors))
+    }
+
+    fn outcome_of(file:&Path, document:CachedDocument, config:&Config, rules:&CompiledRules) -> FileOutcome {
+        match document {
+            CachedDocument::Skipped(classification) => FileOutcome::Skipped(classification),
+            CachedDocument::Parsed(Document::Event(event), mut errors) => {
+                diagnostic::fill_file(&mut errors, file);
+                FileOutcome::Event(event, errors)
             },
-            errors
-        ))
+            CachedDocument::Parsed(Document::NFe(nfe), mut errors) => {
+                let (data, data_errors) = extract_data_with(&nfe, config, rules);
+                errors.extend(data_errors);
+                diagnostic::fill_file(&mut errors, file);
+                FileOutcome::NFe(Box::new(data), errors)
+            }
+        }
     }
 
-    pub fn parse_multiple(files:&Vec<PathBuf>) -> Result<(MultipleData, Vec<Error>), ParseErrors>{
-        
-        let mut all_data = MultipleData::new();
-        let mut errors = Vec::new();
+    /* the same note may come twice (re-downloaded, -nfe.xml and -procNFe.xml,
+     * zipped and loose), only one version per access key is kept: the first
+     * one with protNFe or, when none has it, the first one found
+     */
+    fn kept_versions(outcomes:&[Result<FileOutcome, ParseErrors>]) -> HashMap<Key, usize> {
+        let mut kept : HashMap<Key, usize> = HashMap::new();
 
-        for file in files.iter(){
-            let (data,parse_errors) = parse_file(&file)?;
-            
-            errors.extend(parse_errors);
+        for (index, outcome) in outcomes.iter().enumerate() {
+            let Ok(FileOutcome::NFe(data, errors)) = outcome else { continue };
+            if data.key.is_empty() || broken_xml(errors).is_some() {
+                continue;
+            }
 
-            
-            if !all_data.contains_key(&data.load_number){
-                all_data.insert(data.load_number, vec![data]);
+            match kept.get(&data.key).map(|kept_index| &outcomes[*kept_index]) {
+                None => { kept.insert(data.key.clone(), index); },
+                Some(Ok(FileOutcome::NFe(kept_data, _))) if !kept_data.has_protocol && data.has_protocol => {
+                    kept.insert(data.key.clone(), index);
+                },
+                Some(_) => ()
+            }
+        }
+
+        kept
+    }
+
+    // a syntax error stops the reading halfway, nothing read from that file can be trusted
+    fn broken_xml(errors:&[Diagnostic]) -> Option<&Diagnostic> {
+        errors.iter().find(|error| error.code == Code::XmlRead)
+    }
+
+    fn fail_file(parsed:&mut ParsedFiles, file:&Path, reason:String, position:Option<u64>, load_number:Option<LoadNumber>){
+        let mut failure = Diagnostic::error(Code::FileFailed, format!("{} failed: {}", file.display(), reason)).with_file(file);
+        failure.position = position;
+
+        if let Some(load_number) = load_number {
+            failure = failure.with_load(load_number);
+            parsed.incomplete.push(load_number);
+        }
+
+        parsed.errors.push(failure);
+    }
+
+    // cancelled NF-e leave the apportionment, correction letters are kept as information
+    pub fn apply_events(parsed:&mut ParsedFiles, events:&[NFeEvent]){
+        // an event file that shows up twice is applied only once
+        let mut applied = HashSet::new();
+
+        for event in events.iter().filter(|event| event.is_registered()) {
+            if !applied.insert((&event.key, &event.event_type, &event.sequence)) {
                 continue;
             }
 
+            if event.is_cancellation() {
+                let mut cancelled = CancelledNFe{
+                    key: event.key.clone(),
+                    justification: event.justification.clone(),
+                    cancelled_at: event.issued_at.clone(),
+                    ..Default::default()
+                };
+
+                for (load_number, data_list) in parsed.data.iter_mut() {
+                    if let Some(index) = data_list.iter().position(|data| data.key == event.key) {
+                        let data = data_list.remove(index);
+                        cancelled.danfe = data.danfe;
+                        cancelled.to = data.to;
+                        cancelled.load_number = Some(*load_number);
+                    }
+                }
 
-            if let Some(data_list) = all_data.get_mut(&data.load_number) { data_list.push(data) }
+                parsed.data.retain(|_, data_list| !data_list.is_empty());
+                parsed.cancelled.push(cancelled);
+                continue;
+            }
+
+            if event.is_correction_letter() {
+                for data in parsed.data.values_mut().flatten().filter(|data| data.key == event.key) {
+                    data.corrections.push(event.correction.clone());
+                }
+            }
         }
+    }
 
-        Ok((all_data,errors))
+    pub fn flag_incomplete(loads:&mut Loads, incomplete:&[LoadNumber]){
+        for carrier_loads in loads.values_mut() {
+            for (load_number, load) in carrier_loads.loads.iter_mut() {
+                load.incomplete = incomplete.contains(load_number);
+            }
+        }
+    }
+
+    // the email plate wins, the NF-e one only fills it when the email has none
+    fn license_plate(email_data:&EmailLoadData, data:&Data) -> LicensePlate {
+        if !email_data.license_plate.is_empty() {
+            return email_data.license_plate.clone();
+        }
+        data.plates().first().map(|plate| plate.to_string()).unwrap_or_default()
     }
 
-    fn fix_danfe(danfe:&mut DANFE){
-        if danfe.starts_with("001000") {
+    // the email may name the truck or one of its trailers, any of them is a match
+    fn check_plates(load_number:LoadNumber, email_data:&EmailLoadData, data:&Data, errors:&mut Vec<Diagnostic>) {
+        let plates = data.plates();
+        if email_data.license_plate.is_empty() || plates.is_empty() {
+            return;
+        }
+
+        let email_plate = text::normalise_plate(&email_data.license_plate);
+        if plates.iter().any(|plate| text::normalise_plate(plate) == email_plate) {
             return;
         }
-        *danfe = String::from("001000") + danfe;
+
+        errors.push(
+            Diagnostic::warning(
+                Code::PlateMismatch,
+                format!(
+                    "Load {} has the plate {} on email, but the NF-e {} carries {}",
+                    load_number,
+                    email_plate,
+                    data.key,
+                    plates.iter().map(|plate| text::normalise_plate(plate)).collect::<Vec<String>>().join(", ")
+                )
+            )
+                .with_load(load_number)
+                .with_key(&data.key)
+                .with_field("veicTransp")
+        );
     }
 
-    pub fn concat_data(data:&MultipleData, email_data:&EmailData) -> (Loads, Vec<Error>){
+    pub fn concat_data(data:&MultipleData, email_data:&EmailData) -> (Loads, Vec<Diagnostic>){
         let mut loads = Loads::new();
-        let mut errors = Vec::<Error>::new();
+        let mut errors = Vec::<Diagnostic>::new();
         
         for (load_number, data_loads) in data.iter() {
             
             for d in data_loads{
                 
-                if !loads.contains_key(&d.by){
-                    loads.insert(
-                        d.by.clone(), 
-                        LoadsDataByCarrier::default()
-                    );
+                let carrier_loads = loads.entry(d.carrier_key.clone()).or_default();
+                if !d.by.is_empty() && (carrier_loads.carrier.is_empty() || d.by < carrier_loads.carrier) {
+                    carrier_loads.carrier = d.by.clone();
                 }
                 
                 let load_email_data = match email_data.get(load_number){
                     Some(data) => {data},
                     None => {
-                        errors.push(String::from(format!("Load {} not found on email", load_number)));
+                        errors.push(
+                            Diagnostic::warning(Code::LoadNotInEmail, format!("Load {} not found on email", load_number))
+                                .with_load(*load_number)
+                                .with_key(&d.key)
+                        );
                         continue;
                     }
                 };
 
-                if let Some(carrier_loads) = loads.get_mut(&d.by) { 
+                check_plates(*load_number, load_email_data, d, &mut errors);
+
+                if let Some(carrier_loads) = loads.get_mut(&d.carrier_key) { 
 
-                    let mut danfe = d.danfe.clone();
-                    fix_danfe(&mut danfe);
                     let delivery = Delivery {
-                        danfe: vec![danfe],
+                        danfe: vec![d.danfe.clone()],
+                        document: vec![d.document],
                         key: vec![d.key.clone()],
                         to: d.to.clone(),
+                        recipient: d.recipient.clone(),
+                        delivery_place: d.delivery_place.clone(),
                         quantity: d.quantity,
                         cubicage: d.cubicage,
+                        value: d.value,
+                        net_weight: d.net_weight,
+                        gross_weight: d.gross_weight,
+                        volumes: d.volumes.clone(),
+                        corrections: d.corrections.clone(),
                         ..Default::default()
                     };
 
                     
                     if !carrier_loads.loads.contains_key(load_number) {
                         let mut load = Load{
-                            license_plate: load_email_data.license_plate.clone(),
+                            license_plate: license_plate(load_email_data, d),
                             total_price: load_email_data.price,
                             ..Default::default()
                         };
@@ -403,6 +1206,9 @@ pub mod parsing{
 
                         if let Some(load_data) = carrier_loads.loads.get_mut(load_number) {
                             load_data.deliveries.push(delivery);
+                            if load_data.license_plate.is_empty() {
+                                load_data.license_plate = license_plate(load_email_data, d);
+                            }
                         };
 
                     }
@@ -431,146 +1237,110 @@ pub mod parsing{
 #[cfg(test)]
 mod tests{
     use std::collections::HashMap;
+    use std::fs;
+    use std::io::{BufReader, Cursor};
     use std::path::PathBuf;
+    use std::sync::Mutex;
 
-    use quick_xml::events::BytesText;
+    use quick_xml::events::BytesRef;
 
-    use crate::constants::*;
+    use crate::classify;
+    use crate::cache::{self, CachedDocument, ParseCache};
+    use crate::catalog::ProductCatalog;
+    use crate::config::{Config, ExtractionRules, Rule, RuleSet};
+    use crate::files;
+    use crate::signature::SignatureStatus;
+    use crate::diagnostic::{Code, Diagnostic, Severity};
     use crate::types::*;
     use crate::types::ParseErrors;
 
     use super::*;
    
-    #[test]
-    fn test_get_flags(){
-        let (flag1, flag2) = flags::generate_flags();
+    /* the fixtures predate the signature check, only signed*.xml carry one,
+     * and every note read by a rule reports which one it was
+     */
+    fn problems(errors:&[Diagnostic]) -> Vec<Diagnostic> {
+        errors.iter().filter(|error| ![Code::UnsignedNFe, Code::ExtractionRule].contains(&error.code)).cloned().collect()
+    }
 
-        assert_eq!(flag1, 0);
-        assert_eq!(flag2, 0);
+    fn path(elements:&[&str]) -> tags::ElementPath {
+        let mut path = tags::ElementPath::default();
+        for element in elements {
+            path.open(element.as_bytes());
+        }
+        path
     }
 
     #[test]
-    fn test_update_flags(){
-        let (mut flag1, mut flag2) = flags::generate_flags();
-
-        flags::update_flag(&mut flag1, 1);
-        assert_eq!(flag1,1);
+    fn test_anchored_path(){
+        let path_from_proc = path(&["nfeProc", "NFe", "infNFe", "dest", "xNome"]);
+        assert_eq!(path_from_proc.anchored(), vec!["infNFe", "dest", "xNome"]);
 
-        flags::update_flag(&mut flag2, 255);
-        assert_eq!(flag2,255);
-    }
+        let path_without_proc = path(&["NFe", "infNFe", "ide", "nNF"]);
+        assert_eq!(path_without_proc.anchored(), vec!["infNFe", "ide", "nNF"]);
 
-    #[test]
-    fn test_check_flags(){
-        let (mut flag1, _) = flags::generate_flags();
+        let protocol_path = path(&["nfeProc", "protNFe", "infProt", "chNFe"]);
+        assert_eq!(protocol_path.anchored(), vec!["protNFe", "infProt", "chNFe"]);
 
-        flags::update_flag(&mut flag1, DANFE_FLAG);
-        assert_eq!(flags::check_flag(&flag1, DANFE_FLAG), true);
-        
-        flags::update_flag(&mut flag1, RAZAO_SOCIAL_FLAG);
-        assert_eq!(flags::check_flag(&flag1, RAZAO_SOCIAL_FLAG), true); 
-        assert_eq!(flags::check_flag(&flag1, DANFE_FLAG), true);
-        
-        flags::update_flag(&mut flag1, SHIPPING_COMPANY_FLAG);
-        assert_eq!(flags::check_flag(&flag1, RAZAO_SOCIAL_FLAG), true); 
-        assert_eq!(flags::check_flag(&flag1, DANFE_FLAG), true);
-        assert_eq!(flags::check_flag(&flag1, SHIPPING_COMPANY_FLAG), true);
+        let unknown_path = path(&["dest", "xNome"]);
+        assert!(unknown_path.anchored().is_empty());
     }
 
     #[test]
-    fn test_match_tag(){
-        let (mut flags, mut backtrack) = flags::generate_flags();
-        let mut total_flag = 0;
-        let mut total_backtrack = 0;
-
-        // no tag
-        tags::match_tag(b"invalid_tag", &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-        
-        tags::match_tag(DANFE_TAG, &mut flags, &mut backtrack);
-        total_flag += DANFE_FLAG;
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-        
-        tags::match_tag(LOAD_CUBICAGE_TAG, &mut flags, &mut backtrack);
-        total_flag += LOAD_CUBICAGE_FLAG;
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-        
-        // X_NOME without backtrack
-        tags::match_tag(X_NOME, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-
-        tags::match_tag(RAZAO_SOCIAL_FIRST_TAG, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack+RAZAO_SOCIAL_BACKTRACK_FLAG);
-        
-        // X_NOME for razao social
-        tags::match_tag(X_NOME, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag+RAZAO_SOCIAL_FLAG);
-        assert_eq!(backtrack,total_backtrack+RAZAO_SOCIAL_BACKTRACK_FLAG);
-        
-        flags::update_flag(&mut flags, RAZAO_SOCIAL_FLAG);
-        flags::update_flag(&mut backtrack, RAZAO_SOCIAL_BACKTRACK_FLAG);
-        tags::match_tag(SHIPPING_COMPANY_FIRST_TAG, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack+SHIPPING_COMPANY_BACKTRACK_FLAG);
-        
-        // X_NOME for shipping company
-        tags::match_tag(X_NOME, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag+SHIPPING_COMPANY_FLAG);
-        assert_eq!(backtrack,total_backtrack+SHIPPING_COMPANY_BACKTRACK_FLAG);
-
-        // X_NOME with both flags
-        flags::update_flag(&mut flags, SHIPPING_COMPANY_FLAG);
-        flags::update_flag(&mut backtrack, SHIPPING_COMPANY_BACKTRACK_FLAG);
-        tags::match_tag(RAZAO_SOCIAL_FIRST_TAG, &mut flags, &mut backtrack);
-        tags::match_tag(SHIPPING_COMPANY_FIRST_TAG, &mut flags, &mut backtrack);
-        total_backtrack += RAZAO_SOCIAL_BACKTRACK_FLAG + SHIPPING_COMPANY_BACKTRACK_FLAG;
-        total_flag += RAZAO_SOCIAL_FLAG + SHIPPING_COMPANY_FLAG;
-        tags::match_tag(X_NOME, &mut flags, &mut backtrack);
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-
-        tags::match_tag(QUANTITY_TAG, &mut flags, &mut backtrack);
-        total_flag += QUANTITY_FLAG;
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-
-        tags::match_tag(ACCESS_KEY_TAG, &mut flags, &mut backtrack);
-        total_flag += ACCESS_KEY_FLAG;
-        assert_eq!(flags,total_flag);
-        assert_eq!(backtrack,total_backtrack);
-    }
+    fn test_path_text() -> Result<(), ParseErrors>{
+        let mut path = path(&["infNFe", "dest", "xNome"]);
 
-    #[test]
-    fn test_match_text() -> Result<(), ParseErrors>{
-        let mut flags : u8 = 1;
-        let mut data = HashMap::new();
+        path.push_text("\n\t A ");
+        path.push_reference(&BytesRef::new("amp"))?;
+        path.push_text(" B");
+        path.push_reference(&BytesRef::new("#x41"))?;
 
-        let base_text = "test";
-        let text = BytesText::new(&base_text);
+        assert_eq!(path.take_text(), "A & BA");
+        assert_eq!(path.take_text(), "");
 
-        for power in 0..=7{
-            flags <<= power;
-            tags::match_text(&flags, &text, &mut data)?;
-        }
+        path.close();
+        assert_eq!(path.anchored(), vec!["infNFe", "dest"]);
 
-        for (_,v) in data.iter(){
-            assert_eq!(v, base_text);
-        }
+        Ok(())
+    }
 
-        
-        let all_flags : u8 = 255;
-        let mut data = HashMap::new();
-        tags::match_text(&all_flags, &text, &mut data)?;
-        for (_,v) in data.iter(){
-            assert_eq!(v, base_text);
-        }
+    #[test]
+    fn test_match_text(){
+        let mut nfe = NFe::default();
+        let mut errors = Vec::new();
 
-        Ok(())
+        tags::match_text(&["infNFe", "emit", "xNome"], String::from("emitter"), &mut nfe, &mut errors);
+        tags::match_text(&["infNFe", "dest", "xNome"], String::from("recipient"), &mut nfe, &mut errors);
+        tags::match_text(&["infNFe", "transp", "transporta", "xNome"], String::from("carrier"), &mut nfe, &mut errors);
+        tags::match_text(&["infNFe", "transp", "transporta", "CNPJ"], String::from("123"), &mut nfe, &mut errors);
+        tags::match_text(&["infNFe", "xNome"], String::from("nobody"), &mut nfe, &mut errors);
+
+        assert_eq!(nfe.emitter.name, "emitter");
+        assert_eq!(nfe.recipient.name, "recipient");
+        assert_eq!(nfe.transporter.name, "carrier");
+        assert_eq!(nfe.transporter.cnpj, "123");
+
+        // qVol only counts inside a vol block
+        tags::match_text(&["infNFe", "transp", "vol", "qVol"], String::from("3"), &mut nfe, &mut errors);
+        assert!(nfe.volumes.is_empty());
+
+        nfe.volumes.push(Volume::default());
+        tags::match_text(&["infNFe", "transp", "vol", "qVol"], String::from("3"), &mut nfe, &mut errors);
+        assert_eq!(nfe.volumes[0].quantity, Some(3));
+
+        tags::match_text(&["infNFe", "total", "ICMSTot", "vNF"], String::from("abc"), &mut nfe, &mut errors);
+        assert_eq!(nfe.totals.value, None);
+        assert_eq!(errors.len(), 1);
+
+        // the item vProd is not the total one
+        nfe.items.push(Item::default());
+        tags::match_text(&["infNFe", "det", "prod", "vProd"], String::from("10.50"), &mut nfe, &mut errors);
+        tags::match_text(&["infNFe", "det", "prod", "qCom"], String::from("1,5"), &mut nfe, &mut errors);
+        assert_eq!(nfe.items[0].value, Some(10.5));
+        assert_eq!(nfe.items[0].quantity, None);
+        assert_eq!(nfe.totals.products_value, None);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1].field, Some(String::from("qCom")));
     }
 
     #[test]
@@ -582,7 +1352,9 @@ mod tests{
             Carga: 891234 Placa: 124-asz fRetE:1.342,87
         "#);
 
-        let data = parsing::parse_email(&email).unwrap();
+        let ParsedEmail{ data, headers, errors } = parsing::parse_email(&email);
+        assert!(errors.is_empty());
+        assert_eq!(headers, None);
         
         let first_load = data.get(&123456).unwrap();
         assert_eq!(first_load.price, 1342.87);
@@ -595,12 +1367,43 @@ mod tests{
         let third_load = data.get(&891234).unwrap();
         assert_eq!(third_load.price, 1342.87);
         assert_eq!(third_load.license_plate, "124-asz");
+
+        let without_plate = parsing::parse_email("carga 123456 frete 1.342,87").data;
+        assert_eq!(without_plate[&123456].license_plate, "");
+        assert_eq!(without_plate[&123456].price, 1342.87);
+    }
+
+    #[test]
+    fn test_cross_check_plates() -> Result<(), ParseErrors>{
+        let (data, _) = parsing::parse_file(&PathBuf::from("./test_data/repeated_tags.xml"), &Config::default())?;
+        assert_eq!(data.plates(), vec!["ABC1D23", "DEF4567", "GHI8J90"]);
+        let notes = HashMap::from([(10, vec![data])]);
+        let email = |plate:&str| HashMap::from([(10, EmailLoadData{price: 50.0, license_plate: String::from(plate)})]);
+
+        // the truck or any trailer
+        for plate in ["abc1d23", "def-4567", "GHI 8J90"] {
+            let (loads, errors) = parsing::concat_data(&notes, &email(plate));
+            assert!(errors.is_empty(), "{}", plate);
+            assert_eq!(loads["44555666000181"].loads[&10].license_plate, plate);
+        }
+
+        let (_, errors) = parsing::concat_data(&notes, &email("xyz9999"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::PlateMismatch);
+        assert_eq!(errors[0].load_number, Some(10));
+        assert_eq!(errors[0].key, Some(String::from("35240111222333000181550010000123451000000020")));
+        assert_eq!(errors[0].message, "Load 10 has the plate XYZ9999 on email, but the NF-e 35240111222333000181550010000123451000000020 carries ABC1D23, DEF4567, GHI8J90");
+
+        let (loads, errors) = parsing::concat_data(&notes, &email(""));
+        assert!(errors.is_empty());
+        assert_eq!(loads["44555666000181"].loads[&10].license_plate, "ABC1D23");
+        Ok(())
     }
     
     #[test]
     fn test_parse_file() -> Result<(), ParseErrors>{
         let correct_file_path = PathBuf::from("./test_data/correct.xml");
-        let (data,errors) = parsing::parse_file(&correct_file_path)?; 
+        let (data,errors) = parsing::parse_file(&correct_file_path, &Config::default())?; 
 
         assert_eq!(data.danfe, "00100012345");
         assert_eq!(data.cubicage, 3.431);
@@ -608,13 +1411,14 @@ mod tests{
         assert_eq!(data.by, "test3");
         assert_eq!(data.quantity, 10000);
         assert_eq!(data.load_number, 3245);
-        assert_eq!(data.key, "78493");
+        assert_eq!(data.key, "35240111222333000181550010000123451000000011");
+        assert_eq!(data.access_key.unwrap().number, "000012345");
 
 
-        assert_eq!(errors.len(), 0);
+        assert_eq!(problems(&errors).len(), 0);
         
         let correct_file_path = PathBuf::from("./test_data/correct_second_danfe_form.xml");
-        let (data,errors) = parsing::parse_file(&correct_file_path)?; 
+        let (data,errors) = parsing::parse_file(&correct_file_path, &Config::default())?; 
 
         assert_eq!(data.danfe, "00100028282828");
         assert_eq!(data.cubicage, 3.431);
@@ -622,13 +1426,13 @@ mod tests{
         assert_eq!(data.by, "test3");
         assert_eq!(data.quantity, 10000);
         assert_eq!(data.load_number, 3245);
-        assert_eq!(data.key, "78493");
+        assert_eq!(data.key, "35240111222333000181550010282828281000000010");
 
 
-        assert_eq!(errors.len(), 0);
+        assert_eq!(problems(&errors).len(), 0);
         
         let wrong_file_path = PathBuf::from("./test_data/wrong.xml");
-        let (data,errors) = parsing::parse_file(&wrong_file_path)?; 
+        let (data,errors) = parsing::parse_file(&wrong_file_path, &Config::default())?; 
 
         assert_eq!(data.danfe, "");
         assert_eq!(data.cubicage, 0.0);
@@ -638,7 +1442,636 @@ mod tests{
         assert_eq!(data.load_number, 0);
         assert_eq!(data.key, "");
 
-        assert_eq!(errors.len(), 6); // cubicage and load number are in the same tag
+        assert_eq!(problems(&errors).len(), 6); // cubicage and load number are in the same tag
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_bytes_and_reader() -> Result<(), ParseErrors>{
+        let file_path = PathBuf::from("./test_data/correct.xml");
+        let content = fs::read(&file_path).unwrap();
+
+        let (from_file, _) = parsing::parse_file(&file_path, &Config::default())?;
+        let (from_bytes, errors) = parsing::parse_bytes(&content, &Config::default())?;
+        assert_eq!(problems(&errors).len(), 0);
+        assert_eq!(from_bytes.danfe, from_file.danfe);
+        assert_eq!(from_bytes.to, from_file.to);
+        assert_eq!(from_bytes.key, from_file.key);
+        assert_eq!(from_bytes.cubicage, from_file.cubicage);
+
+        let (from_reader, errors) = parsing::parse_reader(BufReader::new(Cursor::new(content)), &Config::default())?;
+        assert_eq!(problems(&errors).len(), 0);
+        assert_eq!(from_reader.danfe, from_file.danfe);
+        assert_eq!(from_reader.load_number, from_file.load_number);
+
+        let (_, errors) = parsing::parse_bytes(b"<NFe><infNFe><dest></NFe>", &Config::default())?;
+        assert_eq!(errors[0].code, Code::XmlRead);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert!(errors[0].message.starts_with("Failed on read data from xml"));
+        assert!(errors[0].position.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_other_encodings() -> Result<(), ParseErrors>{
+        for file in ["./test_data/latin1.xml", "./test_data/bom.xml", "./test_data/undeclared_latin1.xml"] {
+            let file_path = PathBuf::from(file);
+            let (data, errors) = parsing::parse_file(&file_path, &Config::default())?;
+            assert_eq!(data.to, "JOSÉ AÇÚCAR E CIA");
+            assert_eq!(data.by, "TRANSPORTES SÃO JOÃO");
+            assert_eq!(data.load_number, 3245);
+
+            let (from_bytes, _) = parsing::parse_bytes(&fs::read(&file_path).unwrap(), &Config::default())?;
+            assert_eq!(from_bytes.to, data.to);
+
+            let errors = problems(&errors);
+            if file == "./test_data/undeclared_latin1.xml" {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].code, Code::Encoding);
+                assert_eq!(errors[0].severity, Severity::Warning);
+                assert_eq!(errors[0].message, "Text doesn't match the declared encoding UTF-8, read as ISO-8859-1");
+                assert_eq!(errors[0].file, Some(String::from(file)));
+            } else {
+                assert_eq!(problems(&errors).len(), 0);
+            }
+        }
+
+        assert_eq!(classify::classify_file(&PathBuf::from("./test_data/bom.xml"))?.kind, classify::DocumentKind::NFe);
+        assert_eq!(classify::classify_file(&PathBuf::from("./test_data/latin1.xml"))?.kind, classify::DocumentKind::NFe);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_archives() -> Result<(), ParseErrors>{
+        let files = files::get_xml_files(&PathBuf::from("./test_data/archives"));
+        let parsed = parsing::parse_multiple(&files, &Config::default());
+
+        assert_eq!(parsed.data[&3245][0].to, "test");
+        assert_eq!(parsed.data[&10][0].corrections, vec![String::from("Quantidade correta de volumes: 7")]);
+
+        let errors = problems(&parsed.errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::SkippedDocument);
+        assert_eq!(errors[0].file, Some(String::from("./test_data/archives/broken.zip")));
+
+        let member = PathBuf::from("./test_data/archives/notes.zip!/2024/02/nested/repeated_tags.xml");
+        let (data, _) = parsing::parse_file(&member, &Config::default())?;
+        assert_eq!(data.load_number, 10);
+
+        let (_, errors) = parsing::parse_file(&member, &Config{extraction: ExtractionRules{default: RuleSet::default(), ..Default::default()}, ..Default::default()})?;
+        assert_eq!(errors[0].file, Some(String::from("./test_data/archives/notes.zip!/2024/02/nested/repeated_tags.xml")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_multiple_isolates_failures(){
+        let files = vec![
+            PathBuf::from("./test_data/correct.xml"),
+            PathBuf::from("./test_data/broken_syntax.xml"),
+            PathBuf::from("./test_data/missing.xml"),
+            PathBuf::from("./test_data/repeated_tags.xml"),
+        ];
+
+        let parsed = parsing::parse_multiple(&files, &Config::default());
+        assert_eq!(parsed.data[&3245].len(), 1);
+        assert_eq!(parsed.data[&10].len(), 1);
+        assert_eq!(parsed.incomplete, vec![3245]);
+
+        let failures = parsed.errors.iter().filter(|error| error.code == Code::FileFailed).collect::<Vec<_>>();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].file, Some(String::from("./test_data/broken_syntax.xml")));
+        assert_eq!(failures[0].load_number, Some(3245));
+        assert!(failures[0].position.is_some());
+        assert!(failures[0].message.starts_with("./test_data/broken_syntax.xml failed: Failed on read data from xml"));
+        assert_eq!(failures[1].file, Some(String::from("./test_data/missing.xml")));
+        assert_eq!(failures[1].load_number, None);
+
+        let email = HashMap::from([
+            (3245, EmailLoadData{price: 100.0, license_plate: String::from("abc1234")}),
+            (10, EmailLoadData{price: 50.0, license_plate: String::from("abc1234")}),
+        ]);
+        let (mut loads, _) = parsing::concat_data(&parsed.data, &email);
+        parsing::flag_incomplete(&mut loads, &parsed.incomplete);
+        assert!(loads["test3"].loads[&3245].incomplete);
+        assert!(!loads["44555666000181"].loads[&10].incomplete);
+        assert_eq!(loads["44555666000181"].carrier, "CARRIER");
+    }
+
+    #[test]
+    fn test_parse_multiple_is_deterministic(){
+        let mut files = vec![
+            PathBuf::from("./test_data/correct.xml"),
+            PathBuf::from("./test_data/broken_syntax.xml"),
+            PathBuf::from("./test_data/repeated_tags.xml"),
+            PathBuf::from("./test_data/wrong.xml"),
+            PathBuf::from("./test_data/latin1.xml"),
+            PathBuf::from("./test_data/bom.xml"),
+        ];
+        files.extend(files::get_xml_files(&PathBuf::from("./test_data/archives")));
+
+        let first = parsing::parse_multiple(&files, &Config::default());
+        for _ in 0..5 {
+            let again = parsing::parse_multiple(&files, &Config::default());
+            assert_eq!(again.errors, first.errors);
+
+            let keys = |parsed:&ParsedFiles| parsed.data[&3245].iter().map(|data| data.key.clone()).collect::<Vec<_>>();
+            assert_eq!(keys(&again), keys(&first));
+        }
+    }
+
+    #[test]
+    fn test_cubicage_from_catalog() -> Result<(), ParseErrors>{
+        let (mut nfe, _) = parsing::parse_nfe(&PathBuf::from("./test_data/repeated_tags.xml"))?;
+        let config = Config{
+            catalog: ProductCatalog::from_file(&PathBuf::from("./test_data/catalog.csv")).unwrap(),
+            ..Default::default()
+        };
+        let cubicage_errors = |errors:&[Diagnostic]| errors.iter()
+            .filter(|error| [Code::MissingCubicage, Code::EstimatedCubicage, Code::DeclaredCubicage].contains(&error.code))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (data, errors) = parsing::extract_data(&nfe, &config);
+        assert_eq!(data.cubicage, 1.5);
+        assert_eq!(data.cubicage_source, CubicageSource::Declared);
+        let errors = cubicage_errors(&errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Info);
+        assert_eq!(errors[0].message, "Cubicage declared as 1.500 m3, the product catalog estimates 1.650 m3");
+
+        nfe.additional_info = String::from("carga 10");
+        let (data, errors) = parsing::extract_data(&nfe, &Config::default());
+        assert_eq!(data.cubicage, 0.0);
+        assert_eq!(data.cubicage_source, CubicageSource::Missing);
+        assert_eq!(cubicage_errors(&errors)[0].code, Code::MissingCubicage);
+
+        let (data, errors) = parsing::extract_data(&nfe, &config);
+        assert!((data.cubicage - 1.65).abs() < 1e-5);
+        assert_eq!(data.cubicage_source, CubicageSource::Estimated);
+        assert_eq!(data.gross_weight, 32.5);
+        let errors = cubicage_errors(&errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::EstimatedCubicage);
+        assert_eq!(errors[0].severity, Severity::Warning);
+        assert_eq!(errors[0].load_number, Some(10));
+        assert_eq!(errors[0].message, "Cubicage estimated from the product catalog as 1.650 m3");
+
+        // only part of the items is known and pesoB is missing
+        nfe.items[1].code = String::from("UNKNOWN");
+        nfe.volumes.clear();
+        let (data, errors) = parsing::extract_data(&nfe, &config);
+        assert!((data.cubicage - 0.4).abs() < 1e-5);
+        assert_eq!(data.gross_weight, 25.0);
+        assert_eq!(cubicage_errors(&errors)[0].message, "Cubicage estimated from the product catalog as 0.400 m3, products not in the catalog: UNKNOWN");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_party_ids(){
+        let mut nfe = NFe::default();
+        nfe.emitter.cnpj = String::from("11222333000181");
+        nfe.recipient.cpf = String::from("123.456.789-00");
+        nfe.transporter.cnpj = String::from("12ABC34501DE35");
+
+        let (_, errors) = parsing::extract_data(&nfe, &Config::default());
+        let invalid = errors.iter().filter(|error| error.code == Code::InvalidTaxId).collect::<Vec<_>>();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].severity, Severity::Error);
+        assert_eq!(invalid[0].field, Some(String::from("dest/CPF")));
+        assert_eq!(invalid[0].message, "Invalid CPF 123.456.789-00 in dest: invalid check digits: expected 09, found 00");
+
+        nfe.transporter.cpf = String::from("11111111111");
+        let (_, errors) = parsing::extract_data(&nfe, &Config::default());
+        assert!(errors.iter().any(|error| error.code == Code::InvalidTaxId && error.field == Some(String::from("transporta/CPF"))));
+    }
+
+    #[test]
+    fn test_signature_status() -> Result<(), ParseErrors>{
+        let signature_errors = |errors:&[Diagnostic]| errors.iter()
+            .filter(|error| [Code::UnsignedNFe, Code::InvalidSignature].contains(&error.code))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (data, errors) = parsing::parse_file(&PathBuf::from("./test_data/signed.xml"), &Config::default())?;
+        assert_eq!(data.signature, SignatureStatus::Valid);
+        assert_eq!(data.to, "CLIENTE ASSINADO");
+        assert!(signature_errors(&errors).is_empty());
+
+        let (data, errors) = parsing::parse_file(&PathBuf::from("./test_data/signed_tampered.xml"), &Config::default())?;
+        assert_eq!(data.signature, SignatureStatus::DigestMismatch);
+        let errors = signature_errors(&errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::InvalidSignature);
+        assert_eq!(errors[0].severity, Severity::Warning);
+        assert_eq!(errors[0].key, Some(String::from("35240111222333000181550010000123471000000067")));
+
+        let (data, _) = parsing::parse_file(&PathBuf::from("./test_data/signed_bad_signature.xml"), &Config::default())?;
+        assert_eq!(data.signature, SignatureStatus::SignatureMismatch);
+
+        // the signed original sits outside NFe, the note that's read was edited
+        let (data, errors) = parsing::parse_file(&PathBuf::from("./test_data/signed_relocated.xml"), &Config::default())?;
+        assert!(matches!(data.signature, SignatureStatus::Invalid(_)));
+        assert_eq!(signature_errors(&errors)[0].code, Code::InvalidSignature);
+
+        let (data, errors) = parsing::parse_file(&PathBuf::from("./test_data/correct.xml"), &Config::default())?;
+        assert_eq!(data.signature, SignatureStatus::Missing);
+        let errors = signature_errors(&errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::UnsignedNFe);
+        assert_eq!(errors[0].severity, Severity::Warning);
+
+        // built by hand, there was no xml to check
+        let (data, errors) = parsing::extract_data(&NFe::default(), &Config::default());
+        assert_eq!(data.signature, SignatureStatus::NotChecked);
+        assert!(signature_errors(&errors).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates_by_access_key(){
+        let files = vec![
+            PathBuf::from("./test_data/correct-nfe.xml"),
+            PathBuf::from("./test_data/correct.xml"),
+            PathBuf::from("./test_data/archives/notes.zip!/2024/01/correct.xml"),
+            PathBuf::from("./test_data/correction_event.xml"),
+            PathBuf::from("./test_data/archives/loose.xml"),
+            PathBuf::from("./test_data/repeated_tags.xml"),
+        ];
+
+        let parsed = parsing::parse_multiple(&files, &Config::default());
+        assert_eq!(parsed.data[&3245].len(), 1);
+        assert!(parsed.data[&3245][0].has_protocol);
+        assert_eq!(parsed.data[&10][0].corrections.len(), 1);
+
+        let duplicates = parsed.errors.iter().filter(|error| error.code == Code::DuplicateNFe).collect::<Vec<_>>();
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].message, "./test_data/correct-nfe.xml duplicates ./test_data/correct.xml");
+        assert_eq!(duplicates[0].key, Some(String::from("35240111222333000181550010000123451000000011")));
+        assert_eq!(duplicates[1].file, Some(String::from("./test_data/archives/notes.zip!/2024/01/correct.xml")));
+
+        // the version without protNFe is kept when there's no other
+        let parsed = parsing::parse_multiple(&files[..1], &Config::default());
+        assert!(!parsed.data[&3245][0].has_protocol);
+    }
+
+    #[test]
+    fn test_parse_multiple_cached(){
+        let path = std::env::temp_dir().join(format!("rateio-parse-cache-{}", std::process::id())).join("cache.json");
+        let cache = Mutex::new(ParseCache::open(&path));
+        let files = vec![
+            PathBuf::from("./test_data/correct.xml"),
+            PathBuf::from("./test_data/correction_event.xml"),
+            PathBuf::from("./test_data/repeated_tags.xml"),
+            PathBuf::from("./test_data/wrong.xml"),
+            PathBuf::from("./test_data/missing.xml"),
+        ];
+
+        let first = parsing::parse_multiple_cached(&files, &Config::default(), &cache);
+        assert_eq!(cache::lock(&cache).len(), 4);
+        let uncached = parsing::parse_multiple(&files, &Config::default());
+        assert_eq!(first.errors, uncached.errors);
+
+        // what's in the cache is used as it is, the file isn't read as xml again
+        let hash = cache::content_hash(&fs::read("./test_data/correct.xml").unwrap());
+        let mut entry = cache::lock(&cache).get(&hash).unwrap().clone();
+        if let CachedDocument::Parsed(Document::NFe(nfe), _) = &mut entry.document {
+            nfe.recipient.name = String::from("FROM CACHE");
+        }
+        cache::lock(&cache).insert(hash.clone(), entry);
+
+        let mut config = Config::default();
+        config.danfe.default = String::from("{number}");
+        let second = parsing::parse_multiple_cached(&files, &config, &cache);
+        assert_eq!(second.data[&3245][0].to, "FROM CACHE");
+        assert_eq!(second.data[&3245][0].danfe, "12345");
+        assert_eq!(second.data[&10][0].corrections.len(), 1);
+
+        // the same content under another path is a hit, and the entry follows the path
+        let moved = path.with_file_name("moved.xml");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::copy("./test_data/correct.xml", &moved).unwrap();
+        let third = parsing::parse_multiple_cached(std::slice::from_ref(&moved), &config, &cache);
+        assert_eq!(third.data[&3245][0].to, "FROM CACHE");
+        assert_eq!(cache::lock(&cache).get(&hash).unwrap().file, moved.display().to_string());
+
+        // a run over part of the files, or one that can't read them, keeps the others
+        parsing::parse_multiple_cached(&files[..1], &config, &cache);
+        assert_eq!(cache::lock(&cache).len(), 4);
+        let unreadable : Vec<PathBuf> = files.iter().map(|file| file.with_extension("gone")).collect();
+        parsing::parse_multiple_cached(&unreadable, &config, &cache);
+        assert_eq!(cache::lock(&cache).len(), 4);
+
+        let mut cache = cache::lock(&cache);
+        cache.save().unwrap();
+        assert_eq!(ParseCache::open(&path).len(), 4);
+        cache.clear().unwrap();
+        fs::remove_file(moved).unwrap();
+    }
+
+    #[test]
+    fn test_format_danfe(){
+        let document = DocumentNumber{series: 1, number: 12345};
+
+        assert_eq!(danfe::format_danfe("{series:3}000{number}", &document), "00100012345");
+        assert_eq!(danfe::format_danfe("{series}-{number:9}", &document), "1-000012345");
+        assert_eq!(danfe::format_danfe("{number}", &document), "12345");
+        assert_eq!(danfe::format_danfe("{series:2}{number:3}", &document), "0112345");
+    }
+
+    #[test]
+    fn test_danfe_pattern_by_emitter() -> Result<(), ParseErrors>{
+        let mut config = Config::default();
+        config.danfe.emitters.insert(String::from("11222333000181"), String::from("{series:3}.{number:9}"));
+
+        let correct_file_path = PathBuf::from("./test_data/correct.xml");
+        let (data, errors) = parsing::parse_file(&correct_file_path, &config)?;
+        assert_eq!(problems(&errors).len(), 0);
+        assert_eq!(data.danfe, "001.000012345");
+        assert_eq!(data.document, DocumentNumber{series: 1, number: 12345});
+
+        // without ide, series and number come from the access key
+        let xml = r#"<NFe><infNFe Id="NFe35240111222333000181550020000123451000000018"></infNFe></NFe>"#;
+        let (nfe, _) = parsing::parse_nfe_reader(xml.as_bytes())?;
+        let (data, _) = parsing::extract_data(&nfe, &Config::default());
+        assert_eq!(data.document, DocumentNumber{series: 2, number: 12345});
+        assert_eq!(data.danfe, "00200012345");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extraction_rules(){
+        let extract = |info:&str, emitter:&str, config:&Config| {
+            let nfe = NFe{
+                additional_info: String::from(info),
+                emitter: Party{cnpj: String::from(emitter), ..Default::default()},
+                ..Default::default()
+            };
+            let (data, errors) = parsing::extract_data(&nfe, config);
+            let errors = errors.into_iter().filter(|error| error.field.as_deref() == Some("infCpl") || error.code == Code::InvalidRule).collect::<Vec<_>>();
+            (data, errors)
+        };
+
+        let config = Config::default();
+        for (info, load, cubicage) in [
+            ("cubicagem 3,431 m3 carga 3245", 3245, 3.431),
+            ("Nº CARGA: 3245 Volume: 3.4 M³", 3245, 3.4),
+            ("CARGA Nº 3245 cub. 3,431m³", 3245, 3.431),
+            ("carga:3245 cubicagem: 1.250,5 dm3", 3245, 1.2505),
+        ] {
+            let (data, errors) = extract(info, "", &config);
+            assert_eq!(problems(&errors).len(), 0, "{}", info);
+            assert_eq!(data.load_number, load, "{}", info);
+            assert!((data.cubicage - cubicage).abs() < 0.0001, "{} {}", info, data.cubicage);
+        }
+
+        let (data, errors) = extract("CARGA Nº 3245 cub. 3,431m³", "", &config);
+        assert_eq!(data.load_rule, Some(String::from("numero carga")));
+        assert_eq!(data.cubicage_rule, Some(String::from("cubicagem")));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::ExtractionRule);
+        assert_eq!(errors[0].severity, Severity::Info);
+        assert_eq!(errors[0].load_number, Some(3245));
+        assert_eq!(errors[0].message, "Read from infCpl: load number by rule numero carga, cubicage by rule cubicagem");
+
+        let (data, errors) = extract("sem nada aqui", "", &config);
+        assert_eq!(data.load_rule, None);
+        assert_eq!(errors.iter().map(|error| error.code).collect::<Vec<_>>(), vec![Code::MissingLoad, Code::MissingCubicage]);
+        assert_eq!(errors[0].message, "No matches for load");
+
+        let mut config = Config::default();
+        config.extraction.emitters.insert(String::from("11222333000181"), RuleSet {
+            load: vec![Rule::new("romaneio", r"romaneio +(?P<value>[0-9]+)"), Rule::new("broken", r"(")],
+            cubicage: vec![],
+        });
+
+        let (data, _) = extract("romaneio 77 carga 3245 cubicagem 1,5 m3", "11222333000181", &config);
+        assert_eq!(data.load_number, 77);
+        assert_eq!(data.load_rule, Some(String::from("romaneio")));
+        assert_eq!(data.cubicage_rule, Some(String::from("cubicagem")));
+
+        let (data, errors) = extract("carga 3245 cubicagem 1,5 m3", "11222333000181", &config);
+        assert_eq!(data.load_rule, Some(String::from("carga")));
+        assert!(errors[0].message.starts_with("Invalid pattern on rule broken"));
+
+        let (data, _) = extract("romaneio 77 carga 3245 cubicagem 1,5 m3", "99888777000166", &config);
+        assert_eq!(data.load_number, 3245);
+    }
+
+    #[test]
+    fn test_compiled_rules_by_emitter() -> Result<(), crate::config::ConfigError>{
+        let config = Config::from_file(&PathBuf::from("./test_data/rules.toml"))?;
+        let (rules, errors) = extraction::CompiledRules::new(&config.extraction);
+        assert!(errors.is_empty());
+
+        let names = |rules:Vec<&extraction::CompiledRule>| rules.iter().map(|rule| rule.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(rules.load_rules("11222333000181")), vec!["romaneio", "pedido carga"]);
+        assert_eq!(names(rules.load_rules("99888777000166")), vec!["pedido carga"]);
+        assert_eq!(names(rules.cubicage_rules("11222333000181")), vec!["cubagem"]);
+
+        let (default, _) = extraction::CompiledRules::new(&ExtractionRules::default());
+        assert_eq!(names(default.load_rules("11222333000181")), vec!["carga", "numero carga"]);
+        assert_eq!(names(default.cubicage_rules("")), vec!["cubicagem", "volume"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalise_values(){
+        assert_eq!(extraction::normalise_decimal("3,431"), "3.431");
+        assert_eq!(extraction::normalise_decimal("3.4"), "3.4");
+        assert_eq!(extraction::normalise_decimal("1.234,5"), "1234.5");
+        assert_eq!(extraction::normalise_decimal("1,234.5"), "1234.5");
+        assert_eq!(extraction::normalise_decimal("1.234.567"), "1234567");
+        assert_eq!(extraction::normalise_integer("3.245"), "3245");
+        assert_eq!(extraction::cubic_meters_per("M³"), 1.0);
+        assert_eq!(extraction::cubic_meters_per("dm³"), 0.001);
+    }
+
+    #[test]
+    fn test_access_key_diagnostics() -> Result<(), ParseErrors>{
+        let nfe_xml = |key:&str, number:&str| format!(r#"
+            <nfeProc>
+                <NFe><infNFe><ide><nNF>{}</nNF></ide></infNFe></NFe>
+                <protNFe><infProt><chNFe>{}</chNFe></infProt></protNFe>
+            </nfeProc>"#, number, key);
+
+        let (nfe, _) = parsing::parse_nfe_reader(nfe_xml("35240111222333000181550010000123451000000011", "12345").as_bytes())?;
+        let (data, errors) = parsing::extract_data(&nfe, &Config::default());
+        assert!(data.access_key.is_some());
+        assert!(!errors.iter().any(|error| error.message.contains("NF-e Key")));
+
+        let (nfe, _) = parsing::parse_nfe_reader(nfe_xml("35240111222333000181550010000123451000000011", "999").as_bytes())?;
+        let (_, errors) = parsing::extract_data(&nfe, &Config::default());
+        let mismatch = errors.iter().find(|error| error.code == Code::KeyNumberMismatch).unwrap();
+        assert_eq!(mismatch.message, "NF-e Key 35240111222333000181550010000123451000000011 doesn't match the number 999");
+        assert_eq!(mismatch.key, Some(String::from("35240111222333000181550010000123451000000011")));
+        assert_eq!(mismatch.field, Some(String::from("nNF")));
+
+        let (nfe, _) = parsing::parse_nfe_reader(nfe_xml("35240111222333000181650010000123451000000014", "12345").as_bytes())?;
+        let (_, errors) = parsing::extract_data(&nfe, &Config::default());
+        assert!(errors.iter().any(|error| error.code == Code::KeyModelMismatch && error.message == "NF-e Key 35240111222333000181650010000123451000000014 has model 65 instead of 55"));
+
+        let (nfe, _) = parsing::parse_nfe_reader(nfe_xml("35240111222333000181550010000123451000000012", "12345").as_bytes())?;
+        let (data, errors) = parsing::extract_data(&nfe, &Config::default());
+        assert!(data.access_key.is_none());
+        assert!(errors.iter().any(|error| error.code == Code::InvalidKey && error.message.starts_with("Invalid NF-e Key")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_event() -> Result<(), ParseErrors>{
+        let (document, errors) = parsing::parse_document(&PathBuf::from("./test_data/cancellation_event.xml"))?;
+        assert_eq!(problems(&errors).len(), 0);
+
+        let Document::Event(event) = document else { panic!("Should be an event") };
+        assert_eq!(event.key, "35240111222333000181550010000123451000000011");
+        assert_eq!(event.justification, "Nota emitida com valor incorreto");
+        assert_eq!(event.status, "135");
+        assert!(event.is_cancellation());
+        assert!(event.is_registered());
+
+        let (nfe, errors) = parsing::parse_nfe(&PathBuf::from("./test_data/correction_event.xml"))?;
+        assert_eq!(nfe.access_key(), "");
+        assert_eq!(errors.len(), 1);
+
+        let (document, _) = parsing::parse_document(&PathBuf::from("./test_data/correct.xml"))?;
+        let Document::NFe(_) = document else { panic!("Should be a NF-e") };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_multiple_with_events() -> Result<(), ParseErrors>{
+        let files = vec![
+            PathBuf::from("./test_data/cancellation_event.xml"),
+            PathBuf::from("./test_data/correct.xml"),
+            PathBuf::from("./test_data/correction_event.xml"),
+            PathBuf::from("./test_data/repeated_tags.xml"),
+            PathBuf::from("./test_data/wrong.xml"),
+        ];
+
+        let parsed = parsing::parse_multiple(&files, &Config::default());
+        let errors = problems(&parsed.errors);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::SkippedDocument);
+        assert_eq!(errors[0].message, "./test_data/wrong.xml skipped: unknown document");
+        assert_eq!(errors[0].file, Some(String::from("./test_data/wrong.xml")));
+
+        assert!(!parsed.data.contains_key(&3245));
+        assert_eq!(parsed.cancelled.len(), 1);
+        assert_eq!(parsed.cancelled[0].danfe, "00100012345");
+        assert_eq!(parsed.cancelled[0].load_number, Some(3245));
+        assert_eq!(parsed.cancelled[0].justification, "Nota emitida com valor incorreto");
+
+        let corrected = &parsed.data.get(&10).unwrap()[0];
+        assert_eq!(corrected.corrections, vec![String::from("Quantidade correta de volumes: 7")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregistered_cancellation_is_ignored(){
+        let mut parsed = ParsedFiles::default();
+        parsed.data.insert(1, vec![Data{key: String::from("1"), ..Default::default()}]);
+
+        let event = NFeEvent{
+            key: String::from("1"),
+            event_type: String::from("110111"),
+            status: String::from("573"),
+            ..Default::default()
+        };
+        parsing::apply_events(&mut parsed, &[event]);
+
+        assert_eq!(parsed.data.get(&1).unwrap().len(), 1);
+        assert!(parsed.cancelled.is_empty());
+    }
+
+    #[test]
+    fn test_parse_nfe_repeated_tags() -> Result<(), ParseErrors>{
+        let file_path = PathBuf::from("./test_data/repeated_tags.xml");
+        let (nfe,errors) = parsing::parse_nfe(&file_path)?;
+
+        assert_eq!(problems(&errors).len(), 0);
+        assert_eq!(nfe.identification.model, "55");
+        assert_eq!(nfe.identification.series, "1");
+        assert_eq!(nfe.identification.number, "12345");
+        assert_eq!(nfe.emitter.name, "EMITTER & CO");
+        assert_eq!(nfe.recipient.name, "CLIENT");
+        assert_eq!(nfe.recipient.cpf, "12345678909");
+        assert_eq!(nfe.recipient.ie, "ISENTO");
+        assert_eq!(nfe.recipient.address.street, "RUA DAS FLORES");
+        assert_eq!(nfe.recipient.address.number, "100");
+        assert_eq!(nfe.recipient.address.district, "CENTRO");
+        assert_eq!(nfe.recipient.address.city_code, "3550308");
+        assert_eq!(nfe.recipient.address.city, "SAO PAULO");
+        assert_eq!(nfe.recipient.address.state, "SP");
+        assert_eq!(nfe.recipient.address.zip_code, "01001000");
+
+        let delivery_place = nfe.delivery_place.as_ref().unwrap();
+        assert_eq!(delivery_place.cnpj, "99888777000166");
+        assert_eq!(delivery_place.name, "CLIENT WAREHOUSE");
+        assert_eq!(delivery_place.address.street, "AVENIDA BRASIL");
+        assert_eq!(delivery_place.address.city_code, "3304557");
+        assert_eq!(delivery_place.address.state, "RJ");
+        assert_eq!(nfe.transporter.name, "CARRIER");
+        assert_eq!(nfe.transporter.cnpj, "44555666000181");
+        assert_eq!(nfe.volumes.len(), 2);
+        assert_eq!(nfe.volumes[0].quantity, Some(2));
+        assert_eq!(nfe.volumes[0].species, "CAIXA");
+        assert_eq!(nfe.volumes[0].brand, "ACME");
+        assert_eq!(nfe.volumes[1].quantity, Some(5));
+        assert_eq!(nfe.volumes[1].species, "PALLET");
+        assert_eq!(nfe.volumes[1].net_weight, Some(20.25));
+        assert_eq!(nfe.volumes[1].gross_weight, Some(21.5));
+        assert_eq!(nfe.vehicle, Some(Vehicle{plate: String::from("ABC1D23"), state: String::from("SP"), rntc: String::from("12345678")}));
+        assert_eq!(nfe.trailers.len(), 2);
+        assert_eq!(nfe.trailers[1].plate, "GHI8J90");
+        assert_eq!(nfe.trailers[1].state, "MG");
+        assert_eq!(nfe.items.len(), 2);
+        assert_eq!(nfe.items[0], Item{
+            number: 1,
+            code: String::from("CX-001"),
+            description: String::from("CAIXA DE PAPELAO 40X30"),
+            ncm: String::from("48191000"),
+            cfop: String::from("5102"),
+            quantity: Some(100.0),
+            unit: String::from("UN"),
+            value: Some(730.0),
+            taxable_quantity: Some(25.5),
+            taxable_unit: String::from("KG"),
+        });
+        assert_eq!(nfe.items[1].number, 2);
+        assert_eq!(nfe.items[1].code, "PL-002");
+        assert_eq!(nfe.items[1].value, Some(250.0));
+        assert_eq!(nfe.totals.products_value, Some(980.0));
+        assert_eq!(nfe.totals.value, Some(1012.35));
+        assert_eq!(nfe.invoice_number, "12345");
+        assert_eq!(nfe.additional_info, "carga 10 cubicagem 1,5 m3");
+
+        // no protNFe, so the key comes from the infNFe Id
+        assert_eq!(nfe.access_key(), "35240111222333000181550010000123451000000020");
+
+        let (data,errors) = parsing::extract_data(&nfe, &Config::default());
+        assert_eq!(problems(&errors).len(), 0);
+        assert_eq!(data.to, "CLIENT");
+        assert_eq!(data.by, "CARRIER");
+        assert_eq!(data.recipient.cpf, "12345678909");
+        assert_eq!(data.recipient.address.zip_code, "01001000");
+        assert_eq!(data.delivery_place.as_ref().unwrap().address.zip_code, "20040002");
+        assert_eq!(data.load_number, 10);
+        assert_eq!(data.cubicage, 1.5);
+        assert_eq!(data.value, 1012.35);
+        assert_eq!(data.net_weight, 30.75);
+        assert_eq!(data.gross_weight, 32.5);
+        assert_eq!(data.quantity, 7);
+        assert_eq!(data.volumes.len(), 2);
+        assert_eq!(data.items.len(), 2);
+        assert_eq!(data.predominant_product(), Some("CAIXA DE PAPELAO 40X30"));
 
         Ok(())
     }
@@ -648,35 +2081,49 @@ mod tests{
         let data = HashMap::from([
             (10, vec![
              Data{
-                 danfe: String::from("123"),
+                 danfe: String::from("001000123"),
+                 document: DocumentNumber{series: 1, number: 123},
                  to: String::from("1"),
                  by: String::from("12"),
+                 carrier_key: String::from("12"),
                  quantity: 10,
                  load_number:10,
                  cubicage: 1.3,
-                 key: String::from("123")
+                 key: String::from("123"),
+                 recipient: Party{
+                     cnpj: String::from("11222333000181"),
+                     ie: String::from("123456789"),
+                     ..Default::default()
+                 },
+                 ..Default::default()
              }
             ]),
             (20, vec![
              Data{
-                 danfe: String::from("1235"),
+                 danfe: String::from("0010001235"),
+                 document: DocumentNumber{series: 1, number: 1235},
                  to: String::from("2"),
                  by: String::from("13"),
+                 carrier_key: String::from("13"),
                  quantity: 100,
                  load_number:20,
                  cubicage: 1.35,
-                 key: String::from("1234")
+                 key: String::from("1234"),
+                 ..Default::default()
              }
             ]),
             (30, vec![
              Data{
                  danfe: String::from("0010001212"),
+                 document: DocumentNumber{series: 1, number: 1212},
                  to: String::from("2"),
                  by: String::from("14"),
+                 carrier_key: String::from("14"),
                  quantity: 100,
                  load_number:20,
                  cubicage: 1.35,
-                 key: String::from("1234")
+                 key: String::from("1234"),
+                 ..Default::default()
              }
             ]),
 
@@ -702,6 +2149,12 @@ mod tests{
 
         assert_eq!(errors.len(), 0);
 
+        let (_, missing) = parsing::concat_data(&HashMap::from([(20, data[&20].clone())]), &HashMap::new());
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].code, Code::LoadNotInEmail);
+        assert_eq!(missing[0].load_number, Some(20));
+        assert_eq!(missing[0].key, Some(String::from("1234")));
+
         let from_12 = result.get("12").unwrap().loads.get(&10).unwrap();
         let from_12_seq = &result.get("12").unwrap().sequence;
         assert_eq!(from_12_seq[0], 10);
@@ -721,6 +2174,9 @@ mod tests{
         assert_eq!(from_12_deliveries.quantity,10);
         assert_eq!(from_12_deliveries.price,100.0);
         assert_eq!(from_12_deliveries.cubicage,1.3);
+        assert_eq!(from_12_deliveries.recipient.cnpj,"11222333000181");
+        assert_eq!(from_12_deliveries.recipient.ie,"123456789");
+        assert!(from_12_deliveries.delivery_place.is_none());
 
         let from_13 = result.get("13").unwrap().loads.get(&20).unwrap();
         let from_13_seq = &result.get("13").unwrap().sequence;
@@ -765,26 +2221,60 @@ mod tests{
 
     }
 
+    #[test]
+    fn test_carriers_by_key() {
+        assert_eq!(text::normalise_name("  Transportes São João Ltda. "), "transportes sao joao ltda");
+        assert_eq!(text::normalise_name("TRANSPORTES SAO-JOAO LTDA"), "transportes sao joao ltda");
+
+        let carrier = |name:&str, cnpj:&str| Party{ name: String::from(name), cnpj: String::from(cnpj), ..Default::default() };
+        assert_eq!(carrier("TRANSPORTES X LTDA", "11.222.333/0001-81").carrier_key(), "11222333000181");
+        assert_eq!(carrier("TRANSPORTES X LTDA", "").carrier_key(), carrier("Transportes X Ltda.", "").carrier_key());
+        assert_eq!(Party{ cpf: String::from("123.456.789-09"), ..Default::default() }.carrier_key(), "12345678909");
+
+        let data = |load_number:LoadNumber, party:Party| Data{
+            danfe: load_number.to_string(),
+            document: DocumentNumber{series: 1, number: load_number},
+            by: party.name.clone(),
+            carrier_key: party.carrier_key(),
+            load_number,
+            ..Default::default()
+        };
+        let notes = HashMap::from([
+            (1, vec![data(1, carrier("TRANSPORTES X LTDA", ""))]),
+            (2, vec![data(2, carrier("Transportes X Ltda.", ""))]),
+            (3, vec![data(3, carrier("Transportes Y", "11222333000181"))]),
+            (4, vec![data(4, carrier("TRANSPORTES Y S/A", "11222333000181"))]),
+        ]);
+        let email = HashMap::from_iter((1..=4).map(|load| (load, EmailLoadData{price: 10.0, license_plate: String::new()})));
+
+        let (loads, _) = parsing::concat_data(&notes, &email);
+        assert_eq!(loads.len(), 2);
+        assert_eq!(loads["transportes x ltda"].sequence, vec![1, 2]);
+        assert_eq!(loads["transportes x ltda"].carrier, "TRANSPORTES X LTDA");
+        assert_eq!(loads["11222333000181"].sequence, vec![3, 4]);
+        assert_eq!(loads["11222333000181"].carrier, "TRANSPORTES Y S/A");
+    }
+
 
     #[test]
     fn test_email_no_loads() {
-        let text = text::generate_email_text(&vec![]);
+        let text = text::generate_email_text(&[]);
         assert_eq!(text,String::from(""));
     }
 
 
     #[test]
     fn test_email_single_load() {
-        let text = text::generate_email_text(&vec![1]);
+        let text = text::generate_email_text(&[1]);
         assert_eq!(text,String::from("Segue em anexo CT-e e MDF-e da carga 1.\natt."));
     }
 
     #[test]
     fn test_email_multiple_loads() {
-        let text = text::generate_email_text(&vec![1,2]);
+        let text = text::generate_email_text(&[1,2]);
         assert_eq!(text,String::from("Segue em anexo CT-e e MDF-e das cargas 1 e 2.\natt."));
         
-        let text = text::generate_email_text(&vec![1,2,3]);
+        let text = text::generate_email_text(&[1,2,3]);
         assert_eq!(text,String::from("Segue em anexo CT-e e MDF-e das cargas 1, 2 e 3.\natt."));
     }
 }
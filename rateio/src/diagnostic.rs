@@ -0,0 +1,196 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Key, LoadNumber};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/* stable identifiers for each kind of problem, the dashboard groups by them
+ * so a variant must never be renamed, only added
+ */
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Code {
+    XmlRead,
+    Encoding,
+    UnexpectedEvent,
+    InvalidValue,
+    InvalidRule,
+    MissingInfo,
+    MissingLoad,
+    InvalidLoad,
+    MissingCubicage,
+    InvalidCubicage,
+    MissingQuantity,
+    MissingKey,
+    InvalidKey,
+    KeyModelMismatch,
+    KeyNumberMismatch,
+    MissingDanfe,
+    MissingClient,
+    MissingCarrier,
+    SkippedDocument,
+    FileFailed,
+    DuplicateNFe,
+    LoadNotInEmail,
+    UnsignedNFe,
+    InvalidSignature,
+    InvalidTaxId,
+    PlateMismatch,
+    EstimatedCubicage,
+    DeclaredCubicage,
+    UnparsedEmailLine,
+    EmailDecode,
+    ExtractionRule,
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub file: Option<String>,
+    pub key: Option<Key>,
+    pub load_number: Option<LoadNumber>,
+    pub field: Option<String>,
+    // byte offset inside the xml where the problem was noticed
+    pub position: Option<u64>,
+}
+
+impl fmt::Display for Diagnostic{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f,"{}: {}", file, self.message),
+            None => write!(f,"{}", self.message)
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity:Severity, code:Code, message:String) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            file: None,
+            key: None,
+            load_number: None,
+            field: None,
+            position: None,
+        }
+    }
+
+    pub fn error(code:Code, message:String) -> Self {
+        Diagnostic::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code:Code, message:String) -> Self {
+        Diagnostic::new(Severity::Warning, code, message)
+    }
+
+    pub fn info(code:Code, message:String) -> Self {
+        Diagnostic::new(Severity::Info, code, message)
+    }
+
+    pub fn with_file(mut self, file:&Path) -> Self {
+        self.file = Some(file.display().to_string());
+        self
+    }
+
+    pub fn with_key(mut self, key:&str) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn with_load(mut self, load_number:LoadNumber) -> Self {
+        self.load_number = Some(load_number);
+        self
+    }
+
+    pub fn with_field(mut self, field:&str) -> Self {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    pub fn at(mut self, position:u64) -> Self {
+        self.position = Some(position);
+        self
+    }
+}
+
+/* the context known only after a document is read (its file, its key, its
+ * load) is filled on every diagnostic that doesn't have it yet
+ */
+pub fn fill_file(diagnostics:&mut [Diagnostic], file:&Path) {
+    for diagnostic in diagnostics.iter_mut().filter(|diagnostic| diagnostic.file.is_none()) {
+        diagnostic.file = Some(file.display().to_string());
+    }
+}
+
+pub fn fill_key(diagnostics:&mut [Diagnostic], key:&str) {
+    if key.is_empty() {
+        return;
+    }
+    for diagnostic in diagnostics.iter_mut().filter(|diagnostic| diagnostic.key.is_none()) {
+        diagnostic.key = Some(key.to_string());
+    }
+}
+
+pub fn fill_load(diagnostics:&mut [Diagnostic], load_number:LoadNumber) {
+    for diagnostic in diagnostics.iter_mut().filter(|diagnostic| diagnostic.load_number.is_none()) {
+        diagnostic.load_number = Some(load_number);
+    }
+}
+
+pub fn fill_position(diagnostics:&mut [Diagnostic], position:u64) {
+    for diagnostic in diagnostics.iter_mut().filter(|diagnostic| diagnostic.position.is_none()) {
+        diagnostic.position = Some(position);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_serialization(){
+        let diagnostic = Diagnostic::warning(Code::MissingCubicage, String::from("No matches for cubicage"))
+            .with_file(&PathBuf::from("./test_data/correct.xml"))
+            .with_load(3245)
+            .with_field("infCpl");
+
+        let json = serde_json::to_value(&diagnostic).unwrap();
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["code"], "MISSING_CUBICAGE");
+        assert_eq!(json["file"], "./test_data/correct.xml");
+        assert_eq!(json["load_number"], 3245);
+        assert_eq!(json["key"], serde_json::Value::Null);
+        assert_eq!(diagnostic.to_string(), "./test_data/correct.xml: No matches for cubicage");
+    }
+
+    #[test]
+    fn test_fill_context(){
+        let mut diagnostics = vec![
+            Diagnostic::error(Code::MissingClient, String::from("No client data")),
+            Diagnostic::error(Code::InvalidKey, String::from("Invalid NF-e Key")).with_key("1"),
+        ];
+
+        fill_key(&mut diagnostics, "2");
+        fill_load(&mut diagnostics, 10);
+        fill_position(&mut diagnostics, 42);
+
+        assert_eq!(diagnostics[0].key, Some(String::from("2")));
+        assert_eq!(diagnostics[1].key, Some(String::from("1")));
+        assert!(diagnostics.iter().all(|diagnostic| diagnostic.load_number == Some(10) && diagnostic.position == Some(42)));
+    }
+}
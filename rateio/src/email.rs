@@ -0,0 +1,435 @@
+use crate::diagnostic::{Code, Diagnostic};
+use crate::mime;
+use crate::types::{EmailData, EmailLoadData, LicensePlate, LoadNumber, ParsedEmail, Price};
+
+/* the carrier writes one entry per load, in any order and split over as
+ * many lines as they like, the labels being optional except for "carga":
+ *
+ *   Carga nº 123456 - Placa ABC-1234 - Frete R$ 1.342,87
+ *   carga: 12
+ *   abc1d23 R$800,00
+ *
+ * every "carga" starts a new entry, which takes whatever comes until the next one
+ */
+const LOAD_LABEL:&str = "carga";
+const PLATE_LABEL:&str = "placa";
+const PRICE_LABELS:[&str; 5] = ["frete", "valor", "vlr", "preço", "preco"];
+const CURRENCY:&str = "r$";
+// words allowed between "carga" and its number
+const LOAD_FILLERS:[&str; 7] = ["n", "nº", "n°", "no", "nro", "numero", "número"];
+
+const SEPARATORS:[char; 10] = [':', ';', '|', '=', '(', ')', '/', '*', '-', '#'];
+
+#[derive(Debug,Clone,Copy)]
+struct Token<'a> {
+    text: &'a str,
+    start: usize,
+    end: usize,
+    line: usize,
+}
+
+#[derive(Debug,Default)]
+struct Entry {
+    line: usize,
+    load: Option<LoadNumber>,
+    plate: Option<LicensePlate>,
+    price: Option<Price>,
+    // line the price was guessed on, when it had no label
+    guessed_on: Option<usize>,
+}
+
+// either the body alone or the whole message, as saved from the mail client
+pub fn parse(email_text:&str) -> ParsedEmail {
+    parse_bytes(email_text.as_bytes())
+}
+
+// a saved .eml isn't always utf-8, a body without headers is read as utf-8 or windows-1252
+pub fn parse_bytes(content:&[u8]) -> ParsedEmail {
+    match mime::read_message(content) {
+        Some((headers, body, mut errors)) => {
+            let (data, body_errors) = parse_body(&body);
+            errors.extend(body_errors);
+            ParsedEmail{ data, headers: Some(headers), errors }
+        },
+        None => {
+            let (data, errors) = parse_body(&mime::decode_text(content, None));
+            ParsedEmail{ data, headers: None, errors }
+        }
+    }
+}
+
+fn parse_body(email_text:&str) -> (EmailData, Vec<Diagnostic>) {
+    let text = email_text.to_lowercase();
+    let lines : Vec<&str> = text.lines().collect();
+    let tokens = tokenize(&text);
+
+    let mut data = EmailData::new();
+    let mut errors = Vec::new();
+    let mut entry : Option<Entry> = None;
+    let mut index = 0;
+
+    while index < tokens.len() {
+        let token = tokens[index];
+        index += 1;
+
+        if token.text == LOAD_LABEL {
+            finish(entry.take(), &lines, &mut data, &mut errors);
+            let mut new_entry = Entry{ line: token.line, ..Default::default() };
+
+            while index < tokens.len() && LOAD_FILLERS.contains(&tokens[index].text) {
+                index += 1;
+            }
+            if let Some(load) = tokens.get(index).and_then(|token| parse_load(token.text)) {
+                new_entry.load = Some(load);
+                index += 1;
+            }
+
+            entry = Some(new_entry);
+            continue;
+        }
+
+        let Some(current) = entry.as_mut() else {
+            continue;
+        };
+
+        if token.text == PLATE_LABEL {
+            if let Some((plate, next)) = take_plate(&text, &tokens, index, true) {
+                current.plate = Some(plate);
+                index = next;
+            }
+        } else if PRICE_LABELS.contains(&token.text) || token.text == CURRENCY {
+            while index < tokens.len() && tokens[index].text == CURRENCY {
+                index += 1;
+            }
+            if let Some(price) = tokens.get(index).and_then(|token| parse_money(token.text)) {
+                current.price = Some(price);
+                current.guessed_on = None;
+                index += 1;
+            }
+        } else if let Some(value) = token.text.strip_prefix(CURRENCY) {
+            if let Some(price) = parse_money(value) {
+                current.price = Some(price);
+                current.guessed_on = None;
+            }
+        } else if (current.price.is_none() || current.guessed_on == Some(token.line)) && looks_like_money(token.text) {
+            // a later guess on the same line wins, the first may be a weight or a volume
+            current.price = parse_money(token.text);
+            current.guessed_on = Some(token.line);
+        } else if current.plate.is_none() && let Some((plate, next)) = take_plate(&text, &tokens, index - 1, false) {
+            current.plate = Some(plate);
+            index = next;
+        }
+    }
+
+    finish(entry, &lines, &mut data, &mut errors);
+    (data, errors)
+}
+
+// an entry without a load number or a price is reported, the plate may come from the NF-e
+fn finish(entry:Option<Entry>, lines:&[&str], data:&mut EmailData, errors:&mut Vec<Diagnostic>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    let missing = match (entry.load, entry.price) {
+        (Some(load), Some(price)) => {
+            data.insert(load, EmailLoadData{ price, license_plate: entry.plate.unwrap_or_default() });
+            return;
+        },
+        (None, _) => "load number",
+        (Some(_), None) => "freight value",
+    };
+
+    let mut error = Diagnostic::warning(
+        Code::UnparsedEmailLine,
+        format!("Email line {} mentions carga but has no {}: {}", entry.line, missing, lines.get(entry.line - 1).map_or("", |line| line.trim()))
+    ).with_field("email");
+    if let Some(load) = entry.load {
+        error = error.with_load(load);
+    }
+    errors.push(error);
+}
+
+fn tokenize(text:&str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+
+    for (line_index, line) in text.split_inclusive('\n').enumerate() {
+        let mut start = None;
+        for (position, character) in line.char_indices().chain([(line.len(), ' ')]) {
+            let separator = character.is_whitespace() || SEPARATORS.contains(&character);
+            match (start, separator) {
+                (None, false) => start = Some(position),
+                (Some(first), true) => {
+                    // a sentence may end right after the value
+                    let token = line[first..position].trim_end_matches(['.', ',']);
+                    if !token.is_empty() {
+                        tokens.push(Token{
+                            text: token,
+                            start: offset + first,
+                            end: offset + first + token.len(),
+                            line: line_index + 1,
+                        });
+                    }
+                    start = None;
+                },
+                _ => ()
+            }
+        }
+        offset += line.len();
+    }
+
+    tokens
+}
+
+fn parse_load(text:&str) -> Option<LoadNumber> {
+    if text.is_empty() || !text.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    text.parse::<LoadNumber>().ok()
+}
+
+/* 1.342,87 | 342,87 | 12.345 | 1342,8 | 800 | 800.50
+ * the comma is always the decimal separator, a dot is only a decimal one
+ * when it isn't followed by exactly three digits
+ */
+fn parse_money(text:&str) -> Option<Price> {
+    let text = text.strip_prefix(CURRENCY).unwrap_or(text);
+    if !text.starts_with(|character:char| character.is_ascii_digit()) || !text.chars().all(|character| character.is_ascii_digit() || character == '.' || character == ',') {
+        return None;
+    }
+
+    let (integer, decimals) = match text.split_once(',') {
+        Some((integer, decimals)) => (integer, decimals),
+        None => match text.rsplit_once('.') {
+            Some((integer, decimals)) if decimals.len() != 3 => (integer, decimals),
+            _ => (text, "")
+        }
+    };
+
+    if decimals.len() > 2 || decimals.contains(['.', ',']) {
+        return None;
+    }
+
+    let mut groups = integer.split('.');
+    let first = groups.next().unwrap_or_default();
+    if first.is_empty() || groups.any(|group| group.len() != 3) {
+        return None;
+    }
+
+    format!("{}.{}", integer.replace('.', ""), if decimals.is_empty() { "0" } else { decimals }).parse::<Price>().ok()
+}
+
+/* without a label only a value with two decimals after a comma is taken as
+ * money, a bare number, a weight (12.500) or a date (13.10) may come first
+ */
+fn looks_like_money(text:&str) -> bool {
+    text.split_once(',').is_some_and(|(_, decimals)| decimals.len() == 2) && parse_money(text).is_some()
+}
+
+/* abc1234 and abc1d23 in a single token, or abc 1234 and abc-1234 split in
+ * two. Right after "placa" anything that looks like a plate is taken (124-asz,
+ * 1234asz), without it only the real shape is, so "nota 4567" or "pedido12"
+ * don't hide the plate on the NF-e. The plate is kept as written
+ */
+fn take_plate(text:&str, tokens:&[Token], index:usize, labelled:bool) -> Option<(LicensePlate, usize)> {
+    let is_plate = |value:&str| match labelled {
+        true => (6..=8).contains(&value.len())
+            && value.chars().all(|character| character.is_ascii_alphanumeric())
+            && value.chars().any(|character| character.is_ascii_alphabetic())
+            && value.chars().any(|character| character.is_ascii_digit()),
+        false => is_plate_shape(value),
+    };
+
+    let first = tokens.get(index)?;
+    if is_plate(first.text) {
+        return Some((first.text.to_string(), index + 1));
+    }
+
+    let second = tokens.get(index + 1).filter(|second| second.line == first.line)?;
+    let joined = format!("{}{}", first.text, second.text);
+    let parts_fit = [first.text, second.text].iter().all(|part| (3..=4).contains(&part.len()));
+    if parts_fit && is_plate(&joined) {
+        return Some((text[first.start..second.end].to_string(), index + 2));
+    }
+
+    None
+}
+
+// AAA9999 or, since Mercosul, AAA9A99
+fn is_plate_shape(value:&str) -> bool {
+    let characters : Vec<char> = value.chars().collect();
+    match characters.as_slice() {
+        [first, second, third, fourth, fifth, sixth, seventh] => [first, second, third].iter().all(|character| character.is_ascii_alphabetic())
+            && fourth.is_ascii_digit()
+            && fifth.is_ascii_alphanumeric()
+            && sixth.is_ascii_digit()
+            && seventh.is_ascii_digit(),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_parse_money(){
+        assert_eq!(parse_money("1.342,87"), Some(1342.87));
+        assert_eq!(parse_money("342,87"), Some(342.87));
+        assert_eq!(parse_money("12.345,00"), Some(12345.0));
+        assert_eq!(parse_money("1.234.567,8"), Some(1234567.8));
+        assert_eq!(parse_money("1342,87"), Some(1342.87));
+        assert_eq!(parse_money("r$800"), Some(800.0));
+        assert_eq!(parse_money("12.345"), Some(12345.0));
+        assert_eq!(parse_money("800.50"), Some(800.5));
+
+        assert_eq!(parse_money("1.34,87"), None);
+        assert_eq!(parse_money("1,2,3"), None);
+        assert_eq!(parse_money("abc"), None);
+        assert_eq!(parse_money(",50"), None);
+    }
+
+    #[test]
+    fn test_parse_any_price_format(){
+        let (data, errors) = parse_body(r#"
+            Carga nº 1 - Placa ABC-1234 - Frete R$ 342,87
+            carga: 22 placa abc1d23 frete: r$12.345,00
+            CARGA 333 PLACA XYZ 9876 VALOR R$1342,80.
+            carga 4444 frete 800
+        "#);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(data[&1].price, 342.87);
+        assert_eq!(data[&1].license_plate, "abc-1234");
+        assert_eq!(data[&22].price, 12345.0);
+        assert_eq!(data[&22].license_plate, "abc1d23");
+        assert_eq!(data[&333].price, 1342.8);
+        assert_eq!(data[&333].license_plate, "xyz 9876");
+        assert_eq!(data[&4444].price, 800.0);
+        assert_eq!(data[&4444].license_plate, "");
+    }
+
+    #[test]
+    fn test_parse_loads_split_across_lines(){
+        let (data, errors) = parse_body("Boa tarde,\nsegue a carga\n\ncarga 123456\nplaca: abc1234\nfrete: 1.342,87\n\ncarga 654321\nabc1d23\nR$ 2.000,00\nobrigado");
+
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[&123456].license_plate, "abc1234");
+        assert_eq!(data[&123456].price, 1342.87);
+        assert_eq!(data[&654321].license_plate, "abc1d23");
+        assert_eq!(data[&654321].price, 2000.0);
+
+        // "segue a carga" has no number
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Email line 2 mentions carga but has no load number: segue a carga");
+    }
+
+    #[test]
+    fn test_unlabelled_price_skips_weights_and_dates(){
+        let (data, errors) = parse_body("carga 123456 12.500 kg 2.500,00
+carga 22 13.10 abc1234 800,00
+carga 33 12,50 m3 1.200,00
+carga 44 13.10 frete 90,00 12,00
+carga 55 12.500 kg");
+
+        assert_eq!(data[&123456].price, 2500.0);
+        assert_eq!(data[&22].price, 800.0);
+        assert_eq!(data[&22].license_plate, "abc1234");
+        assert_eq!(data[&33].price, 1200.0);
+        // a labelled price isn't replaced by a guess
+        assert_eq!(data[&44].price, 90.0);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].load_number, Some(55));
+        assert!(errors[0].message.contains("no freight value"));
+    }
+
+    #[test]
+    fn test_plate_needs_label_or_shape(){
+        let (data, errors) = parse_body("carga 123 nota 4567 frete 10,00\ncarga 124 pedido12 frete 10,00 abc1d23\ncarga 125 placa 124-asz frete 10,00");
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(data[&123].license_plate, "");
+        assert_eq!(data[&124].license_plate, "abc1d23");
+        assert_eq!(data[&125].license_plate, "124-asz");
+
+        assert!(is_plate_shape("abc1234"));
+        assert!(is_plate_shape("abc1d23"));
+        assert!(!is_plate_shape("1234asz"));
+        assert!(!is_plate_shape("nota4567"));
+        assert!(!is_plate_shape("pedido12"));
+    }
+
+    #[test]
+    fn test_report_unparsed_lines(){
+        let (data, errors) = parse_body("carga 10 placa abc1234 frete 100,00\ncarga 20 placa abc1234\ncarga 30 frete a combinar\ncargas entregues");
+
+        assert_eq!(data.len(), 1);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error.code == Code::UnparsedEmailLine && error.field == Some(String::from("email"))));
+        assert_eq!(errors[0].message, "Email line 2 mentions carga but has no freight value: carga 20 placa abc1234");
+        assert_eq!(errors[0].load_number, Some(20));
+        assert_eq!(errors[1].load_number, Some(30));
+    }
+
+    #[test]
+    fn test_parse_eml(){
+        let eml = std::fs::read_to_string("./test_data/freight.eml").unwrap();
+        let parsed = parse(&eml);
+
+        assert!(parsed.errors.is_empty(), "{:?}", parsed.errors);
+        let headers = parsed.headers.unwrap();
+        assert_eq!(headers.sender, "João Transportes <fretes@transportadora.com.br>");
+        assert_eq!(headers.date, "Tue, 13 Oct 2026 09:41:07 -0300");
+        assert_eq!(headers.subject, "Fretes das cargas de terça");
+
+        // the plain text part wins over the html one and the attachment is left out
+        assert_eq!(parsed.data.len(), 2);
+        assert_eq!(parsed.data[&123456].price, 1342.87);
+        assert_eq!(parsed.data[&123456].license_plate, "abc1d23");
+        assert_eq!(parsed.data[&654321].price, 2000.0);
+        assert_eq!(parsed.data[&654321].license_plate, "xyz-9876");
+    }
+
+    #[test]
+    fn test_parse_html_only_message(){
+        // "<p>Carga 77 - Placa ABC1234 - Frete R$ 950,00</p><p>Cotação válida</p>" in latin-1
+        let eml = "From: fretes@transportadora.com.br\r\nSubject: Frete\r\nContent-Type: text/html; charset=iso-8859-1\r\nContent-Transfer-Encoding: base64\r\n\r\nPHA+Q2FyZ2EgNzcgLSBQbGFjYSBBQkMxMjM0IC0gRnJldGUgUiQgOTUwLDAwPC9wPjxwPkNvdGHn\r\n428gduFsaWRhPC9wPg==\r\n";
+        let parsed = parse(eml);
+
+        assert!(parsed.errors.is_empty(), "{:?}", parsed.errors);
+        assert_eq!(parsed.headers.as_ref().map(|headers| headers.date.as_str()), Some(""));
+        assert_eq!(parsed.data[&77].price, 950.0);
+        assert_eq!(parsed.data[&77].license_plate, "abc1234");
+
+        let broken = parse("Subject: Frete\nContent-Transfer-Encoding: base64\n\ncarga 77 frete 950,00");
+        assert_eq!(broken.errors.len(), 1);
+        assert_eq!(broken.errors[0].code, Code::EmailDecode);
+        assert_eq!(broken.data[&77].price, 950.0);
+    }
+
+    #[test]
+    fn test_parse_8bit_latin1_message(){
+        let mut eml = b"From: Jo\xe3o <fretes@transportadora.com.br>\r\nSubject: Cota\xe7\xe3o\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\nContent-Type: text/plain; charset=ISO-8859-1\r\nContent-Transfer-Encoding: 8bit\r\n\r\nCarga n\xba 88 - Placa ABC1234 - Frete R$ 300,00 (cota\xe7\xe3o)\r\n--b--\r\n".to_vec();
+        let parsed = parse_bytes(&eml);
+
+        assert!(parsed.errors.is_empty(), "{:?}", parsed.errors);
+        let headers = parsed.headers.unwrap();
+        assert_eq!(headers.sender, "João <fretes@transportadora.com.br>");
+        assert_eq!(headers.subject, "Cotação");
+        assert_eq!(parsed.data[&88].price, 300.0);
+
+        // a pasted body in latin-1 without any header
+        eml = b"carga n\xba 89 frete 10,00 cota\xe7\xe3o".to_vec();
+        assert_eq!(parse_bytes(&eml).data[&89].price, 10.0);
+        assert_eq!(mime::decode_text(&eml, None), "carga nº 89 frete 10,00 cotação");
+    }
+
+    #[test]
+    fn test_plain_body_has_no_headers(){
+        let parsed = parse("Carga: 10\nPlaca: abc1234\nFrete: 100,00");
+        assert_eq!(parsed.headers, None);
+        assert_eq!(parsed.data[&10].price, 100.0);
+    }
+}
@@ -1,11 +1,189 @@
-use std::fs;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
+use rayon::prelude::*;
+use zip::ZipArchive;
+
+// a member of an archive is addressed as archive.zip!/path/file.xml
+pub const ARCHIVE_SEPARATOR:&str = "!/";
+
+/* lists the xml files in the directory and the xml members of every zip in
+ * it (nested folders included). An archive that can't be opened is listed
+ * as it is, so it shows up as a skipped document instead of vanishing
+ */
 pub fn get_xml_files(dir:&PathBuf) -> Vec<PathBuf> {
-    let read_data = fs::read_dir(dir).expect("Failed on read dir"); 
-    let mut files : Vec<PathBuf> = read_data
-        .map(|file| file.expect("Failed on get file").path())
-        .filter(|file| file.to_str().unwrap().find(".xml").is_some()).collect();
+    let read_data = fs::read_dir(dir).expect("Failed on read dir");
+    let mut files = Vec::new();
+
+    for file in read_data.map(|file| file.expect("Failed on get file").path()) {
+        if has_extension(&file, "xml") {
+            files.push(file);
+        } else if has_extension(&file, "zip") {
+            match archive_members(&file) {
+                Ok(members) => files.extend(members),
+                Err(_) => files.push(file),
+            }
+        }
+    }
+
     files.sort();
     files
 }
+
+pub fn archive_members(archive:&Path) -> io::Result<Vec<PathBuf>> {
+    let zip = ZipArchive::new(File::open(archive)?).map_err(io::Error::other)?;
+
+    Ok(zip
+        .file_names()
+        .filter(|name| !name.ends_with('/') && has_extension(Path::new(name), "xml"))
+        .map(|name| PathBuf::from(format!("{}{}{}", archive.display(), ARCHIVE_SEPARATOR, name)))
+        .collect())
+}
+
+// loose files are read from disk, archive members are unpacked in memory
+pub fn read_file(file:&Path) -> io::Result<Vec<u8>> {
+    let Some((archive, member)) = split_archive_path(file) else {
+        return fs::read(file);
+    };
+
+    let mut zip = ZipArchive::new(File::open(archive)?).map_err(io::Error::other)?;
+    let mut entry = zip.by_name(&member).map_err(io::Error::other)?;
+    let mut content = Vec::new();
+    entry.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+// every xml member of the archive, unpacked in a single pass over it
+pub fn read_archive(archive:&Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut zip = ZipArchive::new(File::open(archive)?).map_err(io::Error::other)?;
+    let mut members = Vec::new();
+
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(io::Error::other)?;
+        if entry.is_dir() || !has_extension(Path::new(entry.name()), "xml") {
+            continue;
+        }
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        members.push((entry.name().to_string(), content));
+    }
+
+    Ok(members)
+}
+
+/* same as calling read_file on each one, in the same order, but every
+ * archive is opened once no matter how many of its members are listed
+ */
+pub fn read_files(files:&[PathBuf]) -> Vec<io::Result<Vec<u8>>> {
+    let mut archives : Vec<PathBuf> = files.iter().filter_map(|file| split_archive_path(file)).map(|(archive, _)| archive).collect();
+    archives.sort();
+    archives.dedup();
+
+    let mut unpacked : HashMap<PathBuf, Result<HashMap<String, Vec<u8>>, String>> = archives
+        .par_iter()
+        .map(|archive| (archive.clone(), read_archive(archive).map(HashMap::from_iter).map_err(|error| error.to_string())))
+        .collect();
+
+    // loose files stay None, they're read in parallel below
+    let mut contents : Vec<Option<io::Result<Vec<u8>>>> = files.iter().map(|file| {
+        split_archive_path(file).map(|(archive, member)| match unpacked.get_mut(&archive) {
+            Some(Ok(members)) => members
+                .remove(&member)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in {}", member, archive.display()))),
+            Some(Err(error)) => Err(io::Error::other(error.clone())),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, archive.display().to_string())),
+        })
+    }).collect();
+
+    files.par_iter()
+        .zip(contents.par_iter_mut())
+        .map(|(file, content)| content.take().unwrap_or_else(|| fs::read(file)))
+        .collect()
+}
+
+// only a zip that exists on disk makes the path an archive member
+pub fn split_archive_path(file:&Path) -> Option<(PathBuf, String)> {
+    let path = file.to_str()?;
+    let (archive, member) = path.split_once(ARCHIVE_SEPARATOR)?;
+    let archive = PathBuf::from(archive);
+    if !has_extension(&archive, "zip") || !archive.is_file() {
+        return None;
+    }
+    Some((archive, member.to_string()))
+}
+
+fn has_extension(file:&Path, extension:&str) -> bool {
+    file.extension()
+        .and_then(|found| found.to_str())
+        .is_some_and(|found| found.eq_ignore_ascii_case(extension))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_archive_members() -> io::Result<()>{
+        let archive = PathBuf::from("./test_data/archives/notes.zip");
+        let members = archive_members(&archive)?;
+
+        assert_eq!(members, vec![
+            PathBuf::from("./test_data/archives/notes.zip!/2024/01/correct.xml"),
+            PathBuf::from("./test_data/archives/notes.zip!/2024/02/nested/repeated_tags.xml"),
+        ]);
+
+        assert_eq!(read_file(&members[0])?, fs::read("./test_data/correct.xml")?);
+        assert!(read_file(&PathBuf::from("./test_data/archives/notes.zip!/missing.xml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_xml_files_with_archives(){
+        let files = get_xml_files(&PathBuf::from("./test_data/archives"));
+
+        assert_eq!(files, vec![
+            PathBuf::from("./test_data/archives/broken.zip"),
+            PathBuf::from("./test_data/archives/loose.xml"),
+            PathBuf::from("./test_data/archives/notes.zip!/2024/01/correct.xml"),
+            PathBuf::from("./test_data/archives/notes.zip!/2024/02/nested/repeated_tags.xml"),
+        ]);
+    }
+
+    #[test]
+    fn test_read_files() -> io::Result<()>{
+        let members = archive_members(Path::new("./test_data/archives/notes.zip"))?;
+        let files = vec![
+            members[1].clone(),
+            PathBuf::from("./test_data/correct.xml"),
+            members[0].clone(),
+            PathBuf::from("./test_data/archives/notes.zip!/missing.xml"),
+            PathBuf::from("./test_data/archives/broken.zip!/correct.xml"),
+        ];
+
+        let contents = read_files(&files);
+        assert_eq!(contents.len(), 5);
+        assert_eq!(contents[0].as_ref().ok(), Some(&read_file(&members[1])?));
+        assert_eq!(contents[1].as_ref().ok(), Some(&fs::read("./test_data/correct.xml")?));
+        assert_eq!(contents[2].as_ref().ok(), Some(&fs::read("./test_data/correct.xml")?));
+        assert!(contents[3].is_err());
+        assert!(contents[4].is_err());
+
+        assert_eq!(read_archive(Path::new("./test_data/archives/notes.zip"))?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_archive_path(){
+        assert_eq!(
+            split_archive_path(Path::new("./test_data/archives/notes.zip!/a/b.xml")),
+            Some((PathBuf::from("./test_data/archives/notes.zip"), String::from("a/b.xml")))
+        );
+        assert_eq!(split_archive_path(Path::new("data/correct.xml")), None);
+        // a folder named like that, or a zip that isn't there, is just a path
+        assert_eq!(split_archive_path(Path::new("./test_data/archives!/loose.xml")), None);
+        assert_eq!(split_archive_path(Path::new("./test_data/missing.zip!/a.xml")), None);
+    }
+}
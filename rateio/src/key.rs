@@ -0,0 +1,157 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::Key;
+
+pub const KEY_LENGTH:usize = 44;
+pub const NFE_MODEL:&str = "55";
+
+/* ============ACCESS KEY LAYOUT=====================
+ * cUF    - 2 digits
+ * AAMM   - 4 digits
+ * CNPJ   - 14 digits
+ * mod    - 2 digits
+ * serie  - 3 digits
+ * nNF    - 9 digits
+ * tpEmis - 1 digit
+ * cNF    - 8 digits
+ * cDV    - 1 digit (mod 11 of the previous 43)
+ */
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct ChaveAcesso {
+    pub state: String,
+    pub year_month: String,
+    pub cnpj: String,
+    pub model: String,
+    pub series: String,
+    pub number: String,
+    pub emission_type: String,
+    pub code: String,
+    pub check_digit: u8,
+}
+
+#[derive(Debug,PartialEq)]
+pub enum KeyError {
+    Length(usize),
+    NotNumeric,
+    CheckDigit{expected: u8, found: u8},
+}
+
+impl fmt::Display for KeyError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            KeyError::Length(length) => write!(f,"Access key must have {} digits, found {}", KEY_LENGTH, length),
+            KeyError::NotNumeric => write!(f,"Access key must have only digits"),
+            KeyError::CheckDigit{expected, found} => write!(f,"Invalid access key check digit: expected {}, found {}", expected, found),
+        }
+    }
+}
+
+impl ChaveAcesso {
+    pub fn parse(key:&str) -> Result<Self, KeyError> {
+        let key = key.trim();
+
+        if key.len() != KEY_LENGTH {
+            return Err(KeyError::Length(key.chars().count()));
+        }
+
+        if !key.bytes().all(|digit| digit.is_ascii_digit()) {
+            return Err(KeyError::NotNumeric);
+        }
+
+        let expected = check_digit(&key[..KEY_LENGTH-1]);
+        let found = key.as_bytes()[KEY_LENGTH-1] - b'0';
+        if expected != found {
+            return Err(KeyError::CheckDigit{expected, found});
+        }
+
+        Ok(ChaveAcesso {
+            state: key[0..2].to_string(),
+            year_month: key[2..6].to_string(),
+            cnpj: key[6..20].to_string(),
+            model: key[20..22].to_string(),
+            series: key[22..25].to_string(),
+            number: key[25..34].to_string(),
+            emission_type: key[34..35].to_string(),
+            code: key[35..43].to_string(),
+            check_digit: found,
+        })
+    }
+
+    pub fn is_nfe(&self) -> bool {
+        self.model == NFE_MODEL
+    }
+
+    // compares ignoring the leading zeros, nNF is not padded inside ide
+    pub fn matches_number(&self, number:&str) -> bool {
+        self.number.trim_start_matches('0') == number.trim().trim_start_matches('0')
+    }
+
+    pub fn to_key(&self) -> Key {
+        format!("{}{}{}{}{}{}{}{}{}",
+            self.state, self.year_month, self.cnpj, self.model, self.series,
+            self.number, self.emission_type, self.code, self.check_digit)
+    }
+}
+
+// weights go from 2 to 9, starting from the rightmost digit
+pub fn check_digit(digits:&str) -> u8 {
+    let sum : u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| (digit - b'0') as u32 * (2 + (index as u32 % 8)))
+        .sum();
+
+    match sum % 11 {
+        0 | 1 => 0,
+        rest => (11 - rest) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const VALID_KEY:&str = "35240111222333000181550010000123451000000011";
+
+    #[test]
+    fn test_check_digit(){
+        assert_eq!(check_digit(&VALID_KEY[..43]), 1);
+        assert_eq!(check_digit("3524011122233300018155001028282828100000001"), 0);
+    }
+
+    #[test]
+    fn test_parse_key(){
+        let key = ChaveAcesso::parse(VALID_KEY).unwrap();
+
+        assert_eq!(key.state, "35");
+        assert_eq!(key.year_month, "2401");
+        assert_eq!(key.cnpj, "11222333000181");
+        assert_eq!(key.model, "55");
+        assert_eq!(key.series, "001");
+        assert_eq!(key.number, "000012345");
+        assert_eq!(key.emission_type, "1");
+        assert_eq!(key.code, "00000001");
+        assert_eq!(key.check_digit, 1);
+        assert_eq!(key.to_key(), VALID_KEY);
+
+        assert!(key.is_nfe());
+        assert!(key.matches_number("12345"));
+        assert!(!key.matches_number("12346"));
+    }
+
+    #[test]
+    fn test_invalid_keys(){
+        assert_eq!(ChaveAcesso::parse("78493"), Err(KeyError::Length(5)));
+        assert_eq!(ChaveAcesso::parse("3524011122233300018155001000012345100000001A"), Err(KeyError::NotNumeric));
+        assert_eq!(
+            ChaveAcesso::parse("35240111222333000181550010000123451000000012"),
+            Err(KeyError::CheckDigit{expected: 1, found: 2})
+        );
+
+        let nfce = ChaveAcesso::parse("35240111222333000181650010000123451000000014").unwrap();
+        assert!(!nfce.is_nfe());
+    }
+}
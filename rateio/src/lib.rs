@@ -1,6 +1,16 @@
 mod constants;
+pub mod config;
 mod math;
 pub mod types;
 pub mod data;
 pub mod files;
+pub mod classify;
+pub mod key;
+pub mod diagnostic;
+pub mod cache;
+pub mod signature;
+pub mod tax_id;
+pub mod catalog;
+pub mod email;
+mod mime;
 mod pattern;
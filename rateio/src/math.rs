@@ -15,7 +15,6 @@ pub fn round_price(value:Price) -> Price{
 }
 
 #[cfg(test)]
-
 mod tests{
 
     use super::*;
@@ -61,6 +60,7 @@ mod tests{
     }
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn test_round_values_manually(){
         assert_eq!(round_price(130.45112345), 130.45);
         assert_eq!(round_price(130.45612345), 130.46);
@@ -0,0 +1,437 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+
+use crate::diagnostic::{Code, Diagnostic};
+use crate::types::EmailHeaders;
+
+// any of them in the first block tells a whole message apart from a pasted body
+const MESSAGE_HEADERS:[&str; 5] = ["from", "date", "subject", "mime-version", "content-type"];
+const BYTE_ORDER_MARK:&[u8] = b"\xef\xbb\xbf";
+
+#[derive(Debug,Clone,Default)]
+struct Part {
+    // lowercase names, values unfolded
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/* an RFC 5322 message (a saved .eml or its raw source) becomes its headers
+ * and the text the freight entries are read from. It's read as bytes since
+ * 8bit parts come in whatever charset they declare. None when the content is
+ * just the body someone copied from the mail client
+ */
+pub fn read_message(content:&[u8]) -> Option<(EmailHeaders, String, Vec<Diagnostic>)> {
+    let content = content.strip_prefix(BYTE_ORDER_MARK).unwrap_or(content);
+    let start = content.iter().position(|byte| !matches!(byte, b'\r' | b'\n')).unwrap_or(content.len());
+    let content = &content[start..];
+
+    let message = split_part(content);
+    if !looks_like_message(content, &message) {
+        return None;
+    }
+
+    let headers = EmailHeaders {
+        sender: decode_words(message.header("from").unwrap_or_default()),
+        date: message.header("date").unwrap_or_default().trim().to_string(),
+        subject: decode_words(message.header("subject").unwrap_or_default()),
+    };
+
+    let mut errors = Vec::new();
+    let text = part_text(&message, &mut errors).unwrap_or_default();
+    Some((headers, text, errors))
+}
+
+// the declared charset when it's a known one, else utf-8 and, when that fails, windows-1252 (a superset of latin-1)
+pub fn decode_text(bytes:&[u8], charset:Option<&str>) -> String {
+    if let Some(encoding) = charset.and_then(|charset| Encoding::for_label(charset.trim().as_bytes())) {
+        return encoding.decode(bytes).0.into_owned();
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => WINDOWS_1252.decode(bytes).0.into_owned(),
+    }
+}
+
+fn looks_like_message(content:&[u8], message:&Part) -> bool {
+    let first_line = content.split(|byte| *byte == b'\n').next().unwrap_or_default();
+    let first_is_header = first_line
+        .iter()
+        .position(|byte| *byte == b':')
+        .is_some_and(|colon| colon > 0 && first_line[..colon].iter().all(|character| character.is_ascii_alphanumeric() || *character == b'-'));
+
+    first_is_header && message.headers.iter().any(|(name, _)| MESSAGE_HEADERS.contains(&name.as_str()))
+}
+
+impl Part {
+    fn header(&self, name:&str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    // text/plain when there's no Content-Type
+    fn content_type(&self) -> String {
+        self.header("content-type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| String::from("text/plain"))
+    }
+
+    fn parameter(&self, header:&str, name:&str) -> Option<String> {
+        parameter(self.header(header)?, name)
+    }
+
+    fn is_attachment(&self) -> bool {
+        self.header("content-disposition")
+            .is_some_and(|value| value.trim().to_lowercase().starts_with("attachment"))
+    }
+}
+
+// charset=utf-8 out of text/plain; charset=utf-8
+fn parameter(value:&str, name:&str) -> Option<String> {
+    value
+        .split(';')
+        .skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/* headers up to the first empty line, the body after it. Raw 8bit header
+ * values are decoded with the charset of the part they belong to
+ */
+fn split_part(content:&[u8]) -> Part {
+    let mut raw : Vec<(String, Vec<u8>)> = Vec::new();
+    let mut position = 0;
+
+    while position < content.len() {
+        let end = content[position..].iter().position(|byte| *byte == b'\n').map_or(content.len(), |index| position + index + 1);
+        let line = content[position..end].trim_ascii_end();
+        position = end;
+
+        if line.is_empty() {
+            break;
+        }
+
+        match (line.starts_with(b" ") || line.starts_with(b"\t"), raw.last_mut()) {
+            (true, Some((_, value))) => {
+                value.push(b' ');
+                value.extend_from_slice(line.trim_ascii());
+            },
+            _ => if let Some(colon) = line.iter().position(|byte| *byte == b':') {
+                let name = String::from_utf8_lossy(&line[..colon]).trim().to_lowercase();
+                raw.push((name, line[colon + 1..].trim_ascii().to_vec()));
+            }
+        }
+    }
+
+    let charset = raw.iter()
+        .find(|(name, _)| name == "content-type")
+        .and_then(|(_, value)| parameter(&String::from_utf8_lossy(value), "charset"));
+
+    Part {
+        headers: raw.into_iter().map(|(name, value)| (name, decode_text(&value, charset.as_deref()))).collect(),
+        body: content[position.min(content.len())..].to_vec(),
+    }
+}
+
+/* multipart/alternative keeps the plain text version (the html one when
+ * there's no other), the other multiparts join every inline text part.
+ * A forwarded message (message/rfc822) is read as one more part
+ */
+fn part_text(part:&Part, errors:&mut Vec<Diagnostic>) -> Option<String> {
+    let content_type = part.content_type();
+
+    if content_type.starts_with("multipart/") {
+        let Some(boundary) = part.parameter("content-type", "boundary") else {
+            errors.push(Diagnostic::warning(Code::EmailDecode, format!("{} without a boundary", content_type)).with_field("Content-Type"));
+            return None;
+        };
+        let parts : Vec<Part> = split_multipart(&part.body, &boundary).into_iter().filter(|part| !part.is_attachment()).collect();
+
+        if content_type == "multipart/alternative" {
+            let plain = parts.iter().find(|part| part.content_type() == "text/plain");
+            let preferred = plain.or_else(|| parts.iter().find(|part| part.content_type() == "text/html")).or(parts.first());
+            return preferred.and_then(|part| part_text(part, errors));
+        }
+
+        let texts : Vec<String> = parts.iter().filter_map(|part| part_text(part, errors)).collect();
+        return (!texts.is_empty()).then(|| texts.join("\n"));
+    }
+
+    if content_type == "message/rfc822" {
+        let inner = split_part(&decode_transfer(part, errors));
+        return part_text(&inner, errors);
+    }
+
+    if !content_type.starts_with("text/") {
+        return None;
+    }
+
+    let bytes = decode_transfer(part, errors);
+    let text = decode_text(&bytes, part.parameter("content-type", "charset").as_deref());
+
+    match content_type.as_str() {
+        "text/html" => Some(strip_html(&text)),
+        _ => Some(text)
+    }
+}
+
+fn split_multipart(body:&[u8], boundary:&str) -> Vec<Part> {
+    let delimiter = format!("--{}", boundary);
+    let closing = format!("{}--", delimiter);
+    let mut parts = Vec::new();
+    let mut current : Option<Vec<u8>> = None;
+
+    for line in body.split_inclusive(|byte| *byte == b'\n') {
+        let trimmed = line.trim_ascii_end();
+        if trimmed == delimiter.as_bytes() || trimmed == closing.as_bytes() {
+            if let Some(content) = current.take() {
+                parts.push(split_part(&content));
+            }
+            if trimmed != delimiter.as_bytes() {
+                break;
+            }
+            current = Some(Vec::new());
+        } else if let Some(content) = current.as_mut() {
+            content.extend_from_slice(line);
+        }
+    }
+
+    // a message cut before its closing boundary still has its last part
+    if let Some(content) = current {
+        parts.push(split_part(&content));
+    }
+    parts
+}
+
+fn decode_transfer(part:&Part, errors:&mut Vec<Diagnostic>) -> Vec<u8> {
+    let encoding = part.header("content-transfer-encoding").unwrap_or_default().trim().to_lowercase();
+    match encoding.as_str() {
+        "quoted-printable" => decode_quoted_printable(&part.body),
+        "base64" => {
+            let clean : Vec<u8> = part.body.iter().copied().filter(|byte| !byte.is_ascii_whitespace()).collect();
+            match STANDARD.decode(&clean) {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    errors.push(Diagnostic::warning(Code::EmailDecode, format!("Couldn't decode base64 part, read as it is: {}", error)).with_field("Content-Transfer-Encoding"));
+                    part.body.clone()
+                }
+            }
+        },
+        _ => part.body.clone()
+    }
+}
+
+// =XX is a byte and a = at the end of the line joins it to the next one
+fn decode_quoted_printable(body:&[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut index = 0;
+
+    while index < body.len() {
+        let byte = body[index];
+        if byte != b'=' {
+            decoded.push(byte);
+            index += 1;
+            continue;
+        }
+
+        let rest = &body[index + 1..];
+        if rest.starts_with(b"\r\n") {
+            index += 3;
+        } else if rest.starts_with(b"\n") {
+            index += 2;
+        } else if let Some(value) = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            decoded.push(value);
+            index += 3;
+        } else {
+            decoded.push(byte);
+            index += 1;
+        }
+    }
+
+    decoded
+}
+
+/* =?charset?Q?text?= and =?charset?B?text?= (RFC 2047), as found in
+ * From and Subject. The space between two encoded words is dropped
+ */
+fn decode_words(value:&str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value.trim();
+    let mut previous_was_word = false;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find("=?") else {
+            decoded.push_str(rest);
+            break;
+        };
+
+        let word = rest[start + 2..].splitn(3, '?').collect::<Vec<&str>>();
+        let text_end = match word.as_slice() {
+            [_, _, text] => text.find("?=").map(|end| start + 2 + word[0].len() + word[1].len() + 2 + end),
+            _ => None
+        };
+        let Some(text_end) = text_end else {
+            decoded.push_str(rest);
+            break;
+        };
+
+        let between = &rest[..start];
+        if !(previous_was_word && between.trim().is_empty()) {
+            decoded.push_str(between);
+        }
+
+        let (charset, kind, text) = (word[0], word[1], &rest[start + 2 + word[0].len() + word[1].len() + 2..text_end]);
+        let bytes = match kind.to_ascii_lowercase().as_str() {
+            "b" => STANDARD.decode(text).ok(),
+            "q" => Some(decode_quoted_printable(text.replace('_', " ").as_bytes())),
+            _ => None
+        };
+
+        match bytes {
+            Some(bytes) => {
+                let encoding = Encoding::for_label(charset.as_bytes()).unwrap_or(UTF_8);
+                decoded.push_str(&encoding.decode(&bytes).0);
+            },
+            None => decoded.push_str(&rest[start..text_end + 2]),
+        }
+
+        previous_was_word = true;
+        rest = &rest[text_end + 2..];
+    }
+
+    decoded
+}
+
+/* keeps the text of an html body with the line breaks it shows, which is
+ * all the freight parser needs
+ */
+fn strip_html(html:&str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+
+        let tag = rest[start + 1..start + end].trim().to_lowercase();
+        let name : String = tag.trim_start_matches('/').chars().take_while(|character| character.is_ascii_alphanumeric()).collect();
+        rest = &rest[start + end + 1..];
+
+        // their content is never shown
+        if !tag.starts_with('/') && (name == "script" || name == "style" || name == "head") {
+            let closing = format!("</{}", name);
+            // ascii lowercase keeps the byte offsets of rest
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(position) => &rest[position..],
+                None => "",
+            };
+            continue;
+        }
+
+        if ["br", "p", "div", "tr", "li", "h1", "h2", "h3", "table"].contains(&name.as_str()) {
+            text.push('\n');
+        } else if ["td", "th"].contains(&name.as_str()) {
+            text.push(' ');
+        }
+    }
+    text.push_str(rest);
+
+    decode_entities(&text)
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn decode_entities(text:&str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').filter(|end| *end <= 10).map(|end| (&rest[1..end], end));
+        let character = entity.and_then(|(name, _)| match name {
+            "nbsp" => Some(' '),
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "ordm" => Some('º'),
+            "ccedil" => Some('ç'),
+            "atilde" => Some('ã'),
+            "otilde" => Some('õ'),
+            "aacute" => Some('á'),
+            "eacute" => Some('é'),
+            "iacute" => Some('í'),
+            "oacute" => Some('ó'),
+            "uacute" => Some('ú'),
+            "acirc" => Some('â'),
+            "ecirc" => Some('ê'),
+            "ocirc" => Some('ô'),
+            _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => name.strip_prefix('#').and_then(|number| number.parse::<u32>().ok()).and_then(char::from_u32),
+            }
+        });
+
+        match (character, entity) {
+            (Some(character), Some((_, end))) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            },
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_plain_body_is_not_a_message(){
+        assert!(read_message(b"carga: 123456 placa: abc1234 frete: 1.342,87").is_none());
+        assert!(read_message(b"Carga 123456\nPlaca: abc1234\n\nFrete: 1.342,87").is_none());
+        assert!(read_message(b"").is_none());
+    }
+
+    #[test]
+    fn test_decode_quoted_printable(){
+        assert_eq!(decode_quoted_printable(b"Frete: R$ 1.342,87 =E2=80=94 carga=\n 123"), "Frete: R$ 1.342,87 — carga 123".as_bytes());
+        assert_eq!(decode_quoted_printable(b"a=3Db =ZZ"), b"a=b =ZZ");
+    }
+
+    #[test]
+    fn test_decode_words(){
+        assert_eq!(decode_words("=?UTF-8?Q?Cargas_de_mar=C3=A7o?="), "Cargas de março");
+        assert_eq!(decode_words("=?ISO-8859-1?Q?Jo=E3o?= =?UTF-8?B?U2lsdmE=?= <joao@example.com>"), "JoãoSilva <joao@example.com>");
+        assert_eq!(decode_words("Plain subject"), "Plain subject");
+        assert_eq!(decode_words("broken =?utf-8?q?word"), "broken =?utf-8?q?word");
+    }
+
+    #[test]
+    fn test_strip_html(){
+        let html = "<html><head><style>p { color: red; }</style></head><body><p>Carga n&ordm; 123456</p><div>Placa:&nbsp;<b>ABC1234</b><br>Frete: R&#36; 1.342,87</div><table><tr><td>Carga</td><td>654321</td></tr></table></body></html>";
+        assert_eq!(strip_html(html), "Carga nº 123456\nPlaca: ABC1234\nFrete: R$ 1.342,87\nCarga 654321");
+
+        // İ takes more bytes once lowercased
+        let html = "<style>/* İİİİ İstanbul */ p { color: red; }</STYLE><p>Carga 1 frete 10,00</p>";
+        assert_eq!(strip_html(html), "Carga 1 frete 10,00");
+    }
+}
@@ -1,20 +1,21 @@
-
+// fixed patterns are compiled once and shared by every thread
 pub mod xml{
-    use regex::Regex;
-
-    pub fn load() -> Regex{
-        Regex::new(r"carga *:* *([0-9]+)").unwrap()
-    }
+    use regex::{Error, Regex, RegexBuilder};
 
-    pub fn cubicage() -> Regex{
-        Regex::new(r"cubicagem *:* *([0-9]+,[0-9]+) *m3").unwrap()
+    // extraction rules come from the config, so they may not even compile
+    pub fn rule(pattern:&str) -> Result<Regex, Error>{
+        RegexBuilder::new(pattern).case_insensitive(true).build()
     }
 }
 
-pub mod text{
+pub mod danfe{
+    use std::sync::LazyLock;
+
     use regex::Regex;
 
-    pub fn email_text() -> Regex{
-        Regex::new(r"carga *:* *([0-9]{6}) *placa *:* *([0-9a-z]{3,4}-* *[0-9a-z]{3,4}) *frete *:* *([0-9]\.[0-9]{3},[0-9]{2})").unwrap()
+    static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{(series|number)(?::([0-9]+))?\}").unwrap());
+
+    pub fn placeholder() -> &'static Regex{
+        &PLACEHOLDER
     }
 }
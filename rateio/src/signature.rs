@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use quick_xml::escape::{resolve_predefined_entity, unescape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use x509_cert::Certificate;
+use x509_cert::der::{Decode, Encode};
+
+const SIGNATURE_TAG:&str = "Signature";
+const SIGNED_INFO_TAG:&str = "SignedInfo";
+const NFE_TAG:&str = "NFe";
+const INF_NFE_TAG:&str = "infNFe";
+
+const C14N:&str = "http://www.w3.org/TR/2001/REC-xml-c14n-20010315";
+const ENVELOPED_SIGNATURE:&str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const DIGEST_SHA1:&str = "http://www.w3.org/2000/09/xmldsig#sha1";
+const DIGEST_SHA256:&str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const RSA_SHA1:&str = "http://www.w3.org/2000/09/xmldsig#rsa-sha1";
+const RSA_SHA256:&str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub enum SignatureStatus {
+    // the note didn't come from an xml document, so there was nothing to check
+    #[default]
+    NotChecked,
+    Valid,
+    Missing,
+    // infNFe was changed after it was signed
+    DigestMismatch,
+    // SignedInfo was changed or signed with another key
+    SignatureMismatch,
+    // the signature couldn't be checked at all (unsupported algorithm, broken certificate...)
+    Invalid(String),
+}
+
+impl fmt::Display for SignatureStatus{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            SignatureStatus::NotChecked => write!(f,"signature not checked"),
+            SignatureStatus::Valid => write!(f,"valid signature"),
+            SignatureStatus::Missing => write!(f,"no signature"),
+            SignatureStatus::DigestMismatch => write!(f,"infNFe doesn't match the signed digest"),
+            SignatureStatus::SignatureMismatch => write!(f,"signature doesn't match the certificate"),
+            SignatureStatus::Invalid(reason) => write!(f,"signature can't be checked: {}", reason),
+        }
+    }
+}
+
+// the pieces of ds:Signature needed to check it
+#[derive(Debug,Default)]
+struct SignatureParts {
+    found: bool,
+    canonicalization: String,
+    signature_method: String,
+    reference: String,
+    transforms: Vec<String>,
+    digest_method: String,
+    digest_value: String,
+    signature_value: String,
+    certificate: String,
+}
+
+/* checks the enveloped XMLDSig of NFe/infNFe, id being the Id the parser
+ * read from it, using only what is inside the document: the X509 certificate
+ * it carries. Whether the certificate itself is trusted is not checked, that
+ * would need the ICP-Brasil chain
+ */
+pub fn verify(content:&[u8], id:&str) -> SignatureStatus {
+    let parts = match signature_parts(content) {
+        Ok(parts) => parts,
+        Err(error) => return SignatureStatus::Invalid(error),
+    };
+
+    if !parts.found {
+        return SignatureStatus::Missing;
+    }
+
+    match check(content, &parts, id) {
+        Ok(status) => status,
+        Err(error) => SignatureStatus::Invalid(error),
+    }
+}
+
+fn check(content:&[u8], parts:&SignatureParts, id:&str) -> Result<SignatureStatus, String> {
+    if parts.canonicalization != C14N {
+        return Err(format!("unsupported canonicalization {}", parts.canonicalization));
+    }
+
+    if let Some(transform) = parts.transforms.iter().find(|transform| ![ENVELOPED_SIGNATURE, C14N].contains(&transform.as_str())) {
+        return Err(format!("unsupported transform {}", transform));
+    }
+
+    if parts.reference.strip_prefix('#') != Some(id) {
+        return Err(format!("reference {} isn't the infNFe {}", parts.reference, id));
+    }
+    check_placement(content, id)?;
+
+    let signed = canonicalize(content, &Selector::Id(id))?;
+    let digest = match parts.digest_method.as_str() {
+        DIGEST_SHA1 => Sha1::digest(&signed).to_vec(),
+        DIGEST_SHA256 => Sha256::digest(&signed).to_vec(),
+        other => return Err(format!("unsupported digest {}", other)),
+    };
+
+    if digest != decode_base64(&parts.digest_value)? {
+        return Ok(SignatureStatus::DigestMismatch);
+    }
+
+    let certificate = Certificate::from_der(&decode_base64(&parts.certificate)?)
+        .map_err(|error| format!("invalid certificate: {}", error))?;
+    let public_key_info = certificate.tbs_certificate.subject_public_key_info.to_der()
+        .map_err(|error| format!("invalid certificate: {}", error))?;
+    let public_key = RsaPublicKey::from_public_key_der(&public_key_info)
+        .map_err(|error| format!("certificate without a RSA key: {}", error))?;
+
+    let signed_info = canonicalize(content, &Selector::Named(SIGNED_INFO_TAG))?;
+    let signature = decode_base64(&parts.signature_value)?;
+    let verified = match parts.signature_method.as_str() {
+        RSA_SHA1 => public_key.verify(Pkcs1v15Sign::new::<Sha1>(), &Sha1::digest(&signed_info), &signature),
+        RSA_SHA256 => public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&signed_info), &signature),
+        other => return Err(format!("unsupported signature method {}", other)),
+    };
+
+    match verified {
+        Ok(_) => Ok(SignatureStatus::Valid),
+        Err(_) => Ok(SignatureStatus::SignatureMismatch),
+    }
+}
+
+// base64 inside xml is usually broken in lines
+fn decode_base64(value:&str) -> Result<Vec<u8>, String> {
+    let value : String = value.chars().filter(|character| !character.is_whitespace()).collect();
+    STANDARD.decode(value).map_err(|error| format!("invalid base64: {}", error))
+}
+
+fn reader(content:&[u8]) -> Reader<&[u8]> {
+    let mut reader = Reader::from_reader(content);
+    reader.config_mut().expand_empty_elements = true;
+    reader
+}
+
+fn attribute(reader:&Reader<&[u8]>, tag:&BytesStart, name:&str) -> Result<String, String> {
+    match tag.try_get_attribute(name).map_err(|error| error.to_string())? {
+        Some(value) => Ok(value.decode_and_unescape_value(reader.decoder()).map_err(|error| error.to_string())?.to_string()),
+        None => Ok(String::new())
+    }
+}
+
+// reads only the first ds:Signature, the one that signs the NF-e (protNFe may carry its own)
+fn signature_parts(content:&[u8]) -> Result<SignatureParts, String> {
+    let mut reader = reader(content);
+    let mut parts = SignatureParts::default();
+    let mut path : Vec<String> = Vec::new();
+    let mut buffer = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buffer).map_err(|error| error.to_string())? {
+            Event::Start(tag) => {
+                let name = String::from_utf8_lossy(tag.local_name().as_ref()).to_string();
+                if name == SIGNATURE_TAG && !parts.found {
+                    parts.found = true;
+                    path.clear();
+                }
+
+                if parts.found {
+                    path.push(name);
+                    let inside : Vec<&str> = path.iter().map(String::as_str).collect();
+                    match inside.as_slice() {
+                        [SIGNATURE_TAG, SIGNED_INFO_TAG, "CanonicalizationMethod"] => parts.canonicalization = attribute(&reader, &tag, "Algorithm")?,
+                        [SIGNATURE_TAG, SIGNED_INFO_TAG, "SignatureMethod"] => parts.signature_method = attribute(&reader, &tag, "Algorithm")?,
+                        [SIGNATURE_TAG, SIGNED_INFO_TAG, "Reference"] => parts.reference = attribute(&reader, &tag, "URI")?,
+                        [SIGNATURE_TAG, SIGNED_INFO_TAG, "Reference", "Transforms", "Transform"] => parts.transforms.push(attribute(&reader, &tag, "Algorithm")?),
+                        [SIGNATURE_TAG, SIGNED_INFO_TAG, "Reference", "DigestMethod"] => parts.digest_method = attribute(&reader, &tag, "Algorithm")?,
+                        _ => ()
+                    }
+                }
+            },
+            Event::Text(text) if parts.found => {
+                let text = text.decode().map_err(|error| error.to_string())?;
+                let inside : Vec<&str> = path.iter().map(String::as_str).collect();
+                match inside.as_slice() {
+                    [SIGNATURE_TAG, SIGNED_INFO_TAG, "Reference", "DigestValue"] => parts.digest_value.push_str(&text),
+                    [SIGNATURE_TAG, "SignatureValue"] => parts.signature_value.push_str(&text),
+                    [SIGNATURE_TAG, "KeyInfo", "X509Data", "X509Certificate"] => parts.certificate.push_str(&text),
+                    _ => ()
+                }
+            },
+            Event::End(_) if parts.found => {
+                path.pop();
+                if path.is_empty() {
+                    break;
+                }
+            },
+            Event::Eof => break,
+            _ => ()
+        }
+        buffer.clear();
+    }
+
+    Ok(parts)
+}
+
+/* the digest covers the first element with the Id and the RSA check the first
+ * SignedInfo, so a signed infNFe moved elsewhere (signature wrapping) would
+ * still verify while the parser reads an edited one. Only one element may
+ * carry the Id, and it must be the only infNFe, right under the NFe that
+ * also holds the Signature
+ */
+fn check_placement(content:&[u8], id:&str) -> Result<(), String> {
+    let mut reader = reader(content);
+    let mut buffer = Vec::new();
+
+    // (local name, element index) of every open element
+    let mut open : Vec<(String, usize)> = Vec::new();
+    let mut count = 0;
+    let mut with_id : Vec<(String, Option<(String, usize)>)> = Vec::new();
+    let mut infnfe_count = 0;
+    // (parent index, index) of the first Signature, parent index of the first SignedInfo
+    let mut signature : Option<(Option<usize>, usize)> = None;
+    let mut signed_info : Option<Option<usize>> = None;
+
+    loop {
+        match reader.read_event_into(&mut buffer).map_err(|error| error.to_string())? {
+            Event::Start(tag) => {
+                let name = String::from_utf8_lossy(tag.local_name().as_ref()).to_string();
+                let parent = open.last().cloned();
+
+                if attribute(&reader, &tag, "Id")? == id {
+                    with_id.push((name.clone(), parent.clone()));
+                }
+                if name == INF_NFE_TAG {
+                    infnfe_count += 1;
+                }
+                if name == SIGNATURE_TAG && signature.is_none() {
+                    signature = Some((parent.as_ref().map(|(_, index)| *index), count));
+                }
+                if name == SIGNED_INFO_TAG && signed_info.is_none() {
+                    signed_info = Some(parent.as_ref().map(|(_, index)| *index));
+                }
+
+                open.push((name, count));
+                count += 1;
+            },
+            Event::End(_) => {
+                open.pop();
+            },
+            Event::Eof => break,
+            _ => ()
+        }
+        buffer.clear();
+    }
+
+    let nfe = match with_id.as_slice() {
+        [(name, Some((parent, nfe)))] if name == INF_NFE_TAG && parent == NFE_TAG => *nfe,
+        [_] => return Err(format!("the element with Id {} isn't {}/{}", id, NFE_TAG, INF_NFE_TAG)),
+        found => return Err(format!("{} elements carry the Id {}", found.len(), id)),
+    };
+
+    if infnfe_count != 1 {
+        return Err(format!("{} {} elements in the document", infnfe_count, INF_NFE_TAG));
+    }
+
+    match (signature, signed_info) {
+        (Some((Some(parent), signature)), Some(Some(signed_info_parent))) if parent == nfe && signed_info_parent == signature => Ok(()),
+        _ => Err(format!("the Signature isn't enveloped by the {} that holds {}", NFE_TAG, INF_NFE_TAG)),
+    }
+}
+
+enum Selector<'a> {
+    // the element with this Id attribute
+    Id(&'a str),
+    // the first element with this local name
+    Named(&'a str),
+}
+
+impl Selector<'_> {
+    fn matches(&self, reader:&Reader<&[u8]>, tag:&BytesStart) -> Result<bool, String> {
+        match self {
+            Selector::Id(id) => Ok(attribute(reader, tag, "Id")? == *id),
+            Selector::Named(name) => Ok(tag.local_name().as_ref() == name.as_bytes()),
+        }
+    }
+}
+
+type Namespaces = HashMap<String, String>;
+
+/* Canonical XML 1.0 (without comments) of the selected element, as inclusive
+ * c14n does for a document subset: the apex gets every namespace in scope,
+ * the descendants only the declarations that change what their parent had.
+ * A ds:Signature inside the subset is left out (enveloped-signature)
+ */
+fn canonicalize(content:&[u8], selector:&Selector) -> Result<Vec<u8>, String> {
+    let mut reader = reader(content);
+    let mut buffer = Vec::new();
+    let mut output = String::new();
+
+    // namespaces declared by every open element, rendered ones only for the output
+    let mut scopes : Vec<Namespaces> = Vec::new();
+    let mut rendered : Vec<Namespaces> = Vec::new();
+    let mut depth = 0;
+    let mut skipped = 0;
+
+    loop {
+        let event = reader.read_event_into(&mut buffer).map_err(|error| error.to_string())?;
+        match event {
+            Event::Start(tag) => {
+                let (declarations, attributes) = split_attributes(&reader, &tag)?;
+                scopes.push(declarations.clone());
+
+                if skipped > 0 {
+                    skipped += 1;
+                } else if depth > 0 && tag.local_name().as_ref() == SIGNATURE_TAG.as_bytes() {
+                    skipped = 1;
+                } else if depth > 0 || selector.matches(&reader, &tag)? {
+                    let namespaces = if depth == 0 {
+                        in_scope(&scopes)
+                    } else {
+                        let parent = rendered.last().cloned().unwrap_or_default();
+                        declarations.into_iter()
+                            .filter(|(prefix, uri)| match parent.get(prefix) {
+                                Some(rendered_uri) => rendered_uri != uri,
+                                None => !(prefix.is_empty() && uri.is_empty())
+                            })
+                            .collect()
+                    };
+
+                    let mut element_rendered = rendered.last().cloned().unwrap_or_default();
+                    element_rendered.extend(namespaces.clone());
+                    rendered.push(element_rendered);
+
+                    write_start(&mut output, &tag, &namespaces, attributes, &in_scope(&scopes));
+                    depth += 1;
+                }
+            },
+            Event::End(tag) => {
+                scopes.pop();
+                if skipped > 0 {
+                    skipped -= 1;
+                } else if depth > 0 {
+                    output.push_str("</");
+                    output.push_str(&String::from_utf8_lossy(tag.name().as_ref()));
+                    output.push('>');
+                    rendered.pop();
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(output.into_bytes());
+                    }
+                }
+            },
+            Event::Text(text) if depth > 0 && skipped == 0 => {
+                let text = text.decode().map_err(|error| error.to_string())?;
+                escape_text(&mut output, &text.replace("\r\n", "\n").replace('\r', "\n"));
+            },
+            Event::CData(text) if depth > 0 && skipped == 0 => {
+                let text = text.decode().map_err(|error| error.to_string())?;
+                escape_text(&mut output, &text);
+            },
+            Event::GeneralRef(reference) if depth > 0 && skipped == 0 => {
+                if let Some(character) = reference.resolve_char_ref().map_err(|error| error.to_string())? {
+                    escape_text(&mut output, &character.to_string());
+                } else {
+                    let entity = reference.decode().map_err(|error| error.to_string())?;
+                    match resolve_predefined_entity(&entity) {
+                        Some(value) => escape_text(&mut output, value),
+                        None => return Err(format!("unknown entity {}", entity)),
+                    }
+                }
+            },
+            Event::PI(instruction) if depth > 0 && skipped == 0 => {
+                output.push_str("<?");
+                output.push_str(&String::from_utf8_lossy(&instruction));
+                output.push_str("?>");
+            },
+            Event::Eof => return Err(String::from("signed element not found")),
+            _ => ()
+        }
+        buffer.clear();
+    }
+}
+
+fn in_scope(scopes:&[Namespaces]) -> Namespaces {
+    let mut namespaces = Namespaces::new();
+    for scope in scopes {
+        namespaces.extend(scope.clone());
+    }
+    namespaces.retain(|prefix, uri| !(prefix.is_empty() && uri.is_empty()));
+    namespaces
+}
+
+// namespace declarations (prefix -> uri) apart from the other attributes (qualified name -> value)
+fn split_attributes(reader:&Reader<&[u8]>, tag:&BytesStart) -> Result<(Namespaces, Vec<(String, String)>), String> {
+    let mut declarations = Namespaces::new();
+    let mut attributes = Vec::new();
+
+    for attribute in tag.attributes() {
+        let attribute = attribute.map_err(|error| error.to_string())?;
+        let name = String::from_utf8_lossy(attribute.key.as_ref()).to_string();
+        let raw = reader.decoder().decode(&attribute.value).map_err(|error| error.to_string())?;
+
+        // attribute value normalisation, done by any xml parser before c14n sees the value
+        let normalised = raw.replace("\r\n", " ").replace(['\t', '\n', '\r'], " ");
+        let value = unescape(&normalised).map_err(|error| error.to_string())?.to_string();
+
+        match name.strip_prefix("xmlns") {
+            Some("") => { declarations.insert(String::new(), value); },
+            Some(prefix) if prefix.starts_with(':') => { declarations.insert(prefix[1..].to_string(), value); },
+            _ => attributes.push((name, value)),
+        }
+    }
+
+    Ok((declarations, attributes))
+}
+
+fn write_start(output:&mut String, tag:&BytesStart, namespaces:&Namespaces, mut attributes:Vec<(String, String)>, scope:&Namespaces) {
+    output.push('<');
+    output.push_str(&String::from_utf8_lossy(tag.name().as_ref()));
+
+    let mut namespaces : Vec<(&String, &String)> = namespaces.iter().collect();
+    namespaces.sort();
+    for (prefix, uri) in namespaces {
+        if prefix.is_empty() {
+            output.push_str(" xmlns=\"");
+        } else {
+            output.push_str(&format!(" xmlns:{}=\"", prefix));
+        }
+        escape_attribute(output, uri);
+        output.push('"');
+    }
+
+    // sorted by namespace uri and then local name, unprefixed attributes have no namespace
+    let sort_key = |name:&str| match name.split_once(':') {
+        Some((prefix, local)) => (scope.get(prefix).cloned().unwrap_or_default(), local.to_string()),
+        None => (String::new(), name.to_string()),
+    };
+    attributes.sort_by_key(|(name, _)| sort_key(name));
+
+    for (name, value) in attributes {
+        output.push(' ');
+        output.push_str(&name);
+        output.push_str("=\"");
+        escape_attribute(output, &value);
+        output.push('"');
+    }
+
+    output.push('>');
+}
+
+fn escape_text(output:&mut String, text:&str) {
+    for character in text.chars() {
+        match character {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '\r' => output.push_str("&#xD;"),
+            _ => output.push(character),
+        }
+    }
+}
+
+fn escape_attribute(output:&mut String, value:&str) {
+    for character in value.chars() {
+        match character {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '"' => output.push_str("&quot;"),
+            '\t' => output.push_str("&#x9;"),
+            '\n' => output.push_str("&#xA;"),
+            '\r' => output.push_str("&#xD;"),
+            _ => output.push(character),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::fs;
+
+    use super::*;
+
+    fn canonical(xml:&str, selector:Selector) -> String {
+        String::from_utf8(canonicalize(xml.as_bytes(), &selector).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_canonicalize(){
+        let xml = r#"<?xml version="1.0"?>
+            <NFe xmlns="http://www.portalfiscal.inf.br/nfe"><infNFe versao="4.00" Id="NFe1"><ide><mod>55</mod><esp/></ide><xNome a='x&amp;y' b="1">A &amp; B &#x41; <![CDATA[<c>]]></xNome><!-- comment --></infNFe></NFe>"#;
+
+        assert_eq!(
+            canonical(xml, Selector::Id("NFe1")),
+            r#"<infNFe xmlns="http://www.portalfiscal.inf.br/nfe" Id="NFe1" versao="4.00"><ide><mod>55</mod><esp></esp></ide><xNome a="x&amp;y" b="1">A &amp; B A &lt;c&gt;</xNome></infNFe>"#
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_namespaces(){
+        let xml = r#"<a xmlns="urn:a" xmlns:p="urn:p"><b xmlns:q="urn:q"><c xmlns="urn:a" xmlns:q="urn:other" p:z="1" q:y="2" x="3"/><Signature><d/></Signature></b></a>"#;
+
+        assert_eq!(
+            canonical(xml, Selector::Named("b")),
+            r#"<b xmlns="urn:a" xmlns:p="urn:p" xmlns:q="urn:q"><c xmlns:q="urn:other" x="3" q:y="2" p:z="1"></c></b>"#
+        );
+    }
+
+    const SIGNED_ID:&str = "NFe35240111222333000181550010000123471000000067";
+
+    #[test]
+    fn test_verify_signatures(){
+        let status = |file:&str| verify(&fs::read(file).unwrap(), SIGNED_ID);
+
+        assert_eq!(status("./test_data/signed.xml"), SignatureStatus::Valid);
+        assert_eq!(status("./test_data/signed_tampered.xml"), SignatureStatus::DigestMismatch);
+        assert_eq!(status("./test_data/signed_bad_signature.xml"), SignatureStatus::SignatureMismatch);
+        assert_eq!(status("./test_data/correct.xml"), SignatureStatus::Missing);
+    }
+
+    #[test]
+    fn test_signature_wrapping(){
+        let invalid = |xml:&[u8], id:&str| match verify(xml, id) {
+            SignatureStatus::Invalid(reason) => reason,
+            other => panic!("unexpected status {:?}", other),
+        };
+
+        // the signed infNFe moved out of NFe, the one the parser reads was edited
+        let relocated = fs::read("./test_data/signed_relocated.xml").unwrap();
+        assert_eq!(invalid(&relocated, SIGNED_ID), format!("2 elements carry the Id {}", SIGNED_ID));
+
+        let signed = fs::read_to_string("./test_data/signed.xml").unwrap();
+        assert!(invalid(signed.as_bytes(), "NFe1").starts_with("reference #NFe35240111222333000181550010000123471000000067 isn't"));
+
+        // only the Id was moved away, the parser would still read both infNFe
+        let start = signed.find("\t<infNFe").unwrap();
+        let end = signed.find("</infNFe>").unwrap() + "</infNFe>".len();
+        let copied = format!("{}<Object>{}</Object>{}", &signed[..start], signed[start..end].replace(SIGNED_ID, "NFe2"), &signed[start..]);
+        assert_eq!(invalid(copied.as_bytes(), SIGNED_ID), "2 infNFe elements in the document");
+
+        // the signature has to sit next to infNFe, not inside something else
+        let wrapped = signed.replace("<Signature ", "<Object><Signature ").replace("</Signature>", "</Signature></Object>");
+        assert_eq!(invalid(wrapped.as_bytes(), SIGNED_ID), "the Signature isn't enveloped by the NFe that holds infNFe");
+    }
+
+    #[test]
+    fn test_unsupported_signature(){
+        let xml = fs::read_to_string("./test_data/signed.xml").unwrap()
+            .replace("http://www.w3.org/2000/09/xmldsig#rsa-sha1", "http://www.w3.org/2000/09/xmldsig#dsa-sha1");
+
+        match verify(xml.as_bytes(), SIGNED_ID) {
+            SignatureStatus::Invalid(reason) => assert!(reason.starts_with("unsupported signature method")),
+            other => panic!("unexpected status {:?}", other),
+        }
+    }
+}
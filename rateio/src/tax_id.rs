@@ -0,0 +1,192 @@
+use std::fmt;
+
+use crate::key::check_digit;
+
+pub const CNPJ_LENGTH:usize = 14;
+pub const CPF_LENGTH:usize = 11;
+
+/* ============CNPJ LAYOUT=====================
+ * root   - 8 characters
+ * branch - 4 characters
+ * DV     - 2 digits (mod 11, weights 2 to 9)
+ *
+ * Since July 2026 root and branch may also have uppercase letters, each one
+ * is worth its ascii code minus 48 ('A' = 17), so the same mod 11 covers both
+ *
+ * ============CPF LAYOUT=====================
+ * number - 9 digits
+ * DV     - 2 digits (mod 11, weights 2 to 11)
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub enum TaxIdError {
+    Length{expected: usize, found: usize},
+    InvalidCharacter(char),
+    RepeatedDigits,
+    CheckDigits{expected: String, found: String},
+}
+
+impl fmt::Display for TaxIdError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            TaxIdError::Length{expected, found} => write!(f,"must have {} characters, found {}", expected, found),
+            TaxIdError::InvalidCharacter(character) => write!(f,"invalid character '{}'", character),
+            TaxIdError::RepeatedDigits => write!(f,"all digits are the same"),
+            TaxIdError::CheckDigits{expected, found} => write!(f,"invalid check digits: expected {}, found {}", expected, found),
+        }
+    }
+}
+
+// drops the formatting (11.222.333/0001-81 -> 11222333000181)
+pub fn normalise(id:&str) -> String {
+    id.chars()
+        .filter(|character| !matches!(character, '.' | '/' | '-') && !character.is_whitespace())
+        .map(|character| character.to_ascii_uppercase())
+        .collect()
+}
+
+// returns the normalised CNPJ, numeric or alphanumeric
+pub fn validate_cnpj(cnpj:&str) -> Result<String, TaxIdError> {
+    let cnpj = normalise(cnpj);
+    check_length(&cnpj, CNPJ_LENGTH)?;
+
+    if let Some(character) = cnpj.chars().find(|character| !character.is_ascii_alphanumeric()) {
+        return Err(TaxIdError::InvalidCharacter(character));
+    }
+
+    let (base, digits) = cnpj.split_at(CNPJ_LENGTH - 2);
+    check_digits_only(digits)?;
+    check_repeated(&cnpj)?;
+
+    let first = check_digit(base);
+    let second = check_digit(&format!("{}{}", base, first));
+    compare(format!("{}{}", first, second), digits)?;
+
+    Ok(cnpj)
+}
+
+pub fn validate_cpf(cpf:&str) -> Result<String, TaxIdError> {
+    let cpf = normalise(cpf);
+    check_length(&cpf, CPF_LENGTH)?;
+    check_digits_only(&cpf)?;
+    check_repeated(&cpf)?;
+
+    let (base, digits) = cpf.split_at(CPF_LENGTH - 2);
+    let first = cpf_check_digit(base);
+    let second = cpf_check_digit(&format!("{}{}", base, first));
+    compare(format!("{}{}", first, second), digits)?;
+
+    Ok(cpf)
+}
+
+// 00.000.000/0000-00, anything that isn't a CNPJ comes back as it is
+pub fn format_cnpj(cnpj:&str) -> String {
+    let normalised = normalise(cnpj);
+    if normalised.len() != CNPJ_LENGTH || !normalised.is_ascii() {
+        return cnpj.to_string();
+    }
+    format!("{}.{}.{}/{}-{}", &normalised[0..2], &normalised[2..5], &normalised[5..8], &normalised[8..12], &normalised[12..14])
+}
+
+// 000.000.000-00
+pub fn format_cpf(cpf:&str) -> String {
+    let normalised = normalise(cpf);
+    if normalised.len() != CPF_LENGTH || !normalised.is_ascii() {
+        return cpf.to_string();
+    }
+    format!("{}.{}.{}-{}", &normalised[0..3], &normalised[3..6], &normalised[6..9], &normalised[9..11])
+}
+
+fn check_length(id:&str, expected:usize) -> Result<(), TaxIdError> {
+    let found = id.chars().count();
+    if found != expected {
+        return Err(TaxIdError::Length{expected, found});
+    }
+    Ok(())
+}
+
+fn check_digits_only(id:&str) -> Result<(), TaxIdError> {
+    match id.chars().find(|character| !character.is_ascii_digit()) {
+        Some(character) => Err(TaxIdError::InvalidCharacter(character)),
+        None => Ok(())
+    }
+}
+
+// 00000000000 and friends pass the mod 11 but are never issued
+fn check_repeated(id:&str) -> Result<(), TaxIdError> {
+    if id.chars().all(|character| id.starts_with(character)) {
+        return Err(TaxIdError::RepeatedDigits);
+    }
+    Ok(())
+}
+
+fn compare(expected:String, found:&str) -> Result<(), TaxIdError> {
+    if expected != found {
+        return Err(TaxIdError::CheckDigits{expected, found: found.to_string()});
+    }
+    Ok(())
+}
+
+// weights go from 2 up to the length + 1, without wrapping like the CNPJ does
+fn cpf_check_digit(digits:&str) -> u8 {
+    let sum : u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| (digit - b'0') as u32 * (2 + index as u32))
+        .sum();
+
+    match sum % 11 {
+        0 | 1 => 0,
+        rest => (11 - rest) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_validate_cnpj(){
+        assert_eq!(validate_cnpj("11222333000181"), Ok(String::from("11222333000181")));
+        assert_eq!(validate_cnpj("11.222.333/0001-81"), Ok(String::from("11222333000181")));
+        assert_eq!(
+            validate_cnpj("11222333000182"),
+            Err(TaxIdError::CheckDigits{expected: String::from("81"), found: String::from("82")})
+        );
+        assert_eq!(validate_cnpj("1122233300018"), Err(TaxIdError::Length{expected: 14, found: 13}));
+        assert_eq!(validate_cnpj("00000000000000"), Err(TaxIdError::RepeatedDigits));
+        assert_eq!(validate_cnpj("112223330001AB"), Err(TaxIdError::InvalidCharacter('A')));
+        assert_eq!(validate_cnpj("11222333_00181"), Err(TaxIdError::InvalidCharacter('_')));
+        assert_eq!(validate_cnpj("1122233300018É"), Err(TaxIdError::InvalidCharacter('É')));
+    }
+
+    #[test]
+    fn test_validate_alphanumeric_cnpj(){
+        assert_eq!(validate_cnpj("12ABC34501DE35"), Ok(String::from("12ABC34501DE35")));
+        assert_eq!(validate_cnpj("12.abc.345/01de-35"), Ok(String::from("12ABC34501DE35")));
+        assert!(matches!(validate_cnpj("12ABC34501DF35"), Err(TaxIdError::CheckDigits{..})));
+    }
+
+    #[test]
+    fn test_validate_cpf(){
+        assert_eq!(validate_cpf("12345678909"), Ok(String::from("12345678909")));
+        assert_eq!(validate_cpf("123.456.789-09"), Ok(String::from("12345678909")));
+        assert_eq!(
+            validate_cpf("12345678900"),
+            Err(TaxIdError::CheckDigits{expected: String::from("09"), found: String::from("00")})
+        );
+        assert_eq!(validate_cpf("11111111111"), Err(TaxIdError::RepeatedDigits));
+        assert_eq!(validate_cpf("1234567890A"), Err(TaxIdError::InvalidCharacter('A')));
+        assert_eq!(validate_cpf("123"), Err(TaxIdError::Length{expected: 11, found: 3}));
+    }
+
+    #[test]
+    fn test_format(){
+        assert_eq!(format_cnpj("11222333000181"), "11.222.333/0001-81");
+        assert_eq!(format_cnpj("12abc34501de35"), "12.ABC.345/01DE-35");
+        assert_eq!(format_cnpj("11.222.333/0001-81"), "11.222.333/0001-81");
+        assert_eq!(format_cnpj("123"), "123");
+        assert_eq!(format_cpf("12345678909"), "123.456.789-09");
+        assert_eq!(format_cpf("123"), "123");
+    }
+}
@@ -6,26 +6,35 @@ use std::fmt;
 
 use quick_xml::errors::Error as quick_xml_ERROR;
 use quick_xml::encoding::EncodingError;
+use quick_xml::events::attributes::AttrError;
 
 use serde::{Deserialize, Serialize};
 
 use crate::math::round_price;
-use crate::data::text::generate_email_text;
+use crate::constants::*;
+use crate::diagnostic::Diagnostic;
+use crate::key::ChaveAcesso;
+use crate::signature::SignatureStatus;
+use crate::data::text::{generate_email_text, normalise_name};
+use crate::tax_id::normalise as normalise_id;
 
 pub type TagName<'a> = &'a [u8];
 
 pub type LoadNumber = u32;
-pub type Quantity = u16;
+pub type Quantity = u32;
 pub type Cubicage = f32;
 pub type Price = f32;
+pub type Weight = f32;
 pub type Carrier = String;
+// transporter CNPJ (or CPF), the normalised name when there's none
+pub type CarrierKey = String;
 pub type Client = String;
 pub type LicensePlate = String;
 pub type DANFE = String;
+pub type Series = u16;
+pub type NFNumber = u32;
 pub type Key = String;
 
-pub type Error = String;
-
 #[derive(Debug)]
 pub enum ParseErrors{
    ParseInt(ParseIntError),
@@ -69,33 +78,298 @@ impl From<EncodingError> for ParseErrors {
     }
 }
 
+impl From<std::io::Error> for ParseErrors {
+    fn from(e: std::io::Error) -> Self {
+        ParseErrors::XMLError(e.into())
+    }
+}
+
+impl From<AttrError> for ParseErrors {
+    fn from(e: AttrError) -> Self {
+        ParseErrors::XMLError(e.into())
+    }
+}
+
 #[derive(Debug,Clone,Default,Serialize,Deserialize)]
 pub struct Packet{
     pub loads: Loads,
-    pub errors: Vec<String>,
+    pub cancelled: Vec<CancelledNFe>,
+    pub errors: Vec<Diagnostic>,
+    // set when the email came as a whole message (.eml)
+    #[serde(default)]
+    pub email: Option<EmailHeaders>,
+}
+
+// -------------------NF-E DOCUMENT-----------------------------
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct NFe {
+    pub id: String,
+    pub identification: Identification,
+    pub emitter: Party,
+    pub recipient: Party,
+    pub transporter: Party,
+    pub delivery_place: Option<Party>,
+    pub volumes: Vec<Volume>,
+    #[serde(default)]
+    pub vehicle: Option<Vehicle>,
+    #[serde(default)]
+    pub trailers: Vec<Vehicle>,
+    #[serde(default)]
+    pub items: Vec<Item>,
+    pub totals: Totals,
+    pub invoice_number: String,
+    pub additional_info: String,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub signature: SignatureStatus,
+}
+
+// ide
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Identification {
+    pub model: String,
+    pub series: String,
+    pub number: String,
+    pub issued_at: String,
+}
+
+// emit, dest, entrega and transp/transporta
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Party {
+    pub name: String,
+    pub cnpj: String,
+    pub cpf: String,
+    pub ie: String,
+    pub address: Address,
+}
+
+// enderEmit, enderDest and the address fields of entrega
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Address {
+    pub street: String,
+    pub number: String,
+    pub complement: String,
+    pub district: String,
+    pub city_code: String,
+    pub city: String,
+    pub state: String,
+    pub zip_code: String,
+}
+
+// transp/vol
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Volume {
+    pub quantity: Option<Quantity>,
+    pub species: String,
+    pub brand: String,
+    pub net_weight: Option<Weight>,
+    pub gross_weight: Option<Weight>,
+}
+
+// transp/veicTransp and transp/reboque
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct Vehicle {
+    pub plate: LicensePlate,
+    pub state: String,
+    pub rntc: String,
+}
+
+// det/prod, quantities and values are per item line
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct Item {
+    pub number: u32,
+    pub code: String,
+    pub description: String,
+    pub ncm: String,
+    pub cfop: String,
+    pub quantity: Option<f32>,
+    pub unit: String,
+    pub value: Option<Price>,
+    pub taxable_quantity: Option<f32>,
+    pub taxable_unit: String,
+}
+
+// total/ICMSTot
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Totals {
+    pub products_value: Option<Price>,
+    pub value: Option<Price>,
+}
+
+// protNFe/infProt
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct Protocol {
+    pub key: Key,
+    pub number: String,
+    pub status: String,
+    pub received_at: String,
+}
+
+impl Party {
+    // CNPJ, then CPF, then the name, so the same party spelled differently is still one
+    pub fn key(&self) -> String {
+        if !self.cnpj.trim().is_empty() {
+            return normalise_id(&self.cnpj);
+        }
+        if !self.cpf.trim().is_empty() {
+            return normalise_id(&self.cpf);
+        }
+        normalise_name(&self.name)
+    }
+
+    pub fn carrier_key(&self) -> CarrierKey {
+        self.key()
+    }
+}
+
+impl NFe {
+    pub fn access_key(&self) -> Key {
+        if !self.protocol.key.is_empty() {
+            return self.protocol.key.clone();
+        }
+
+        match self.id.strip_prefix(ACCESS_KEY_ID_PREFIX) {
+            Some(key) => key.to_string(),
+            None => self.id.clone()
+        }
+    }
+}
+
+// -------------------NF-E EVENTS-------------------------------
+
+// procEventoNFe/evento/infEvento and retEvento/infEvento
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct NFeEvent {
+    pub key: Key,
+    pub event_type: String,
+    pub sequence: String,
+    pub description: String,
+    pub correction: String,
+    pub justification: String,
+    pub issued_at: String,
+    pub status: String,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub enum Document {
+    NFe(Box<NFe>),
+    Event(NFeEvent),
+}
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct CancelledNFe {
+    pub key: Key,
+    pub danfe: DANFE,
+    pub to: Client,
+    pub load_number: Option<LoadNumber>,
+    pub justification: String,
+    pub cancelled_at: String,
+}
+
+impl NFeEvent {
+    pub fn is_cancellation(&self) -> bool {
+        self.event_type == CANCELLATION_EVENT
+    }
+
+    pub fn is_correction_letter(&self) -> bool {
+        self.event_type == CORRECTION_LETTER_EVENT
+    }
+
+    // events without retEvento are taken as registered
+    pub fn is_registered(&self) -> bool {
+        self.status.is_empty() || REGISTERED_EVENT_STATUS.contains(&self.status.as_str())
+    }
+}
+
+// series and nNF, ordered by series first
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,PartialOrd,Ord,Hash,Serialize,Deserialize)]
+pub struct DocumentNumber {
+    pub series: Series,
+    pub number: NFNumber,
 }
 
 // -------------------INTERMEDIATE OBJS-------------------------
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,Default)]
 pub struct Data {
     pub danfe: DANFE,
+    pub document: DocumentNumber,
     pub to: Client,
+    pub recipient: Party,
+    pub delivery_place: Option<Party>,
     pub by: Carrier,
+    pub carrier_key: CarrierKey,
     pub quantity: Quantity,
     pub load_number: LoadNumber,
+    // name of the extraction rules that found the load and the cubicage
+    pub load_rule: Option<String>,
     pub cubicage: Cubicage,
-    pub key: Key
+    pub cubicage_rule: Option<String>,
+    pub cubicage_source: CubicageSource,
+    pub key: Key,
+    pub access_key: Option<ChaveAcesso>,
+    // came with protNFe, the authorization protocol
+    pub has_protocol: bool,
+    pub signature: SignatureStatus,
+    pub value: Price,
+    pub net_weight: Weight,
+    pub gross_weight: Weight,
+    pub volumes: Vec<Volume>,
+    pub vehicle: Option<Vehicle>,
+    pub trailers: Vec<Vehicle>,
+    pub items: Vec<Item>,
+    pub corrections: Vec<String>,
+}
+
+impl Data {
+    /* xProd of the item with the highest vProd, what the CT-e asks as
+     * "produto predominante"
+     */
+    pub fn predominant_product(&self) -> Option<&str> {
+        self.items
+            .iter()
+            .max_by(|first, second| first.value.unwrap_or(0.0).total_cmp(&second.value.unwrap_or(0.0)))
+            .map(|item| item.description.as_str())
+    }
+
+    // the truck first, then its trailers
+    pub fn plates(&self) -> Vec<&LicensePlate> {
+        self.vehicle.iter().chain(self.trailers.iter()).map(|vehicle| &vehicle.plate).collect()
+    }
+}
+
+// where Data.cubicage came from
+#[derive(Debug,Clone,Copy,Default,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CubicageSource {
+    #[default]
+    Missing,
+    // written in infCpl
+    Declared,
+    // computed from the items with the product catalog
+    Estimated,
 }
 
 pub type MultipleData = HashMap<LoadNumber, Vec<Data>>;
 
+#[derive(Debug,Clone,Default)]
+pub struct ParsedFiles {
+    pub data: MultipleData,
+    pub cancelled: Vec<CancelledNFe>,
+    pub errors: Vec<Diagnostic>,
+    // loads with at least one file that couldn't be parsed
+    pub incomplete: Vec<LoadNumber>,
+}
+
 // -------------------FOR LOADS---------------------------------
 
-pub type Loads = HashMap<Carrier, LoadsDataByCarrier>;
+pub type Loads = HashMap<CarrierKey, LoadsDataByCarrier>;
 
 #[derive(Debug,Clone,Default,Serialize,Deserialize)]
 pub struct LoadsDataByCarrier{
+    // one of the xNome of its notes (the lowest, so it doesn't change between runs), only for display
+    pub carrier: Carrier,
     pub loads: LoadsByNumberData,
     pub sequence: Vec<LoadNumber>,
     pub email: String
@@ -109,6 +383,9 @@ pub struct Load{
     pub license_plate: LicensePlate,
     pub total_price: Price,
     pub total_cubicage: Cubicage,
+    pub total_value: Price,
+    pub total_weight: Weight,
+    pub incomplete: bool,
 }
 
 // -------------------FOR DELIVERY---------------------------------
@@ -116,11 +393,19 @@ pub struct Load{
 #[derive(Debug,Clone,Default,Serialize,Deserialize)]
 pub struct Delivery {
     pub danfe: Vec<DANFE>,
+    pub document: Vec<DocumentNumber>,
     pub key: Vec<Key>,
     pub to: Client,
+    pub recipient: Party,
+    pub delivery_place: Option<Party>,
     pub quantity: Quantity,
     pub price: Price,
-    pub cubicage: Cubicage
+    pub cubicage: Cubicage,
+    pub value: Price,
+    pub net_weight: Weight,
+    pub gross_weight: Weight,
+    pub volumes: Vec<Volume>,
+    pub corrections: Vec<String>,
 }
 
 // -------------------FOR EMAIL--------------------------------------
@@ -133,18 +418,33 @@ pub struct EmailLoadData{
     pub license_plate: LicensePlate
 }
 
+// kept so a price can be traced back to the message it came from
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct EmailHeaders{
+    pub sender: String,
+    pub date: String,
+    pub subject: String,
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct ParsedEmail{
+    pub data: EmailData,
+    // None when only the body was pasted
+    pub headers: Option<EmailHeaders>,
+    pub errors: Vec<Diagnostic>,
+}
+
 
 // -------------------DANFES SEQUENCE HOLDER--------------------------
 
 type LinkedListElement = RefCell<Option<Rc<Node>>>;
 type LinkedListElementBackwards = RefCell<Option<Weak<Node>>>;
-type DANFENumber = u128;
 
 #[derive(Debug)]
 pub struct Node{
     pub value: DANFE,
     pub load: LoadNumber, 
-    value_number: DANFENumber,
+    document: DocumentNumber,
     pub next: LinkedListElement,
     pub previous: LinkedListElementBackwards
 }
@@ -168,17 +468,17 @@ impl LinkedList {
         if let Some(head) = old_head{ 
             node.next = Some(Rc::clone(&head)).into();
             let node_pointer = Rc::new(node);
-            *head.previous.borrow_mut() = Some(Rc::downgrade(&node_pointer)).into();
-            *self.head.borrow_mut() = Some(node_pointer).into(); 
+            *head.previous.borrow_mut() = Some(Rc::downgrade(&node_pointer));
+            *self.head.borrow_mut() = Some(node_pointer); 
                                                                 
         }
-        else { return; } //TODO: return an error maybe
+        //TODO: return an error maybe when there's no head
     }
 
     fn switch_tail(&mut self, last_node:&Rc<Node>,  mut node:Node){
 
         node.previous = Some(Rc::downgrade(last_node)).into();
-        *last_node.next.borrow_mut() = Some(Rc::new(node)).into();
+        *last_node.next.borrow_mut() = Some(Rc::new(node));
     }
 
     fn insert_middle(&mut self, middle_node:&Rc<Node>, mut node:Node){
@@ -189,62 +489,56 @@ impl LinkedList {
             node.next = Some(Rc::clone(middle_node_next)).into();
 
             let new_node_pointer = Rc::new(node);
-            *middle_node_next.previous.borrow_mut() = Some(Rc::downgrade(&new_node_pointer)).into();
-            *middle_node.next.borrow_mut() = Some(new_node_pointer).into();
+            *middle_node_next.previous.borrow_mut() = Some(Rc::downgrade(&new_node_pointer));
+            *middle_node.next.borrow_mut() = Some(new_node_pointer);
         }else{
             let new_node_pointer = Rc::new(node);
-            *middle_node.next.borrow_mut() = Some(new_node_pointer).into();
+            *middle_node.next.borrow_mut() = Some(new_node_pointer);
         }
 
     }
 
 
-    pub fn add_between(&mut self, value:DANFE, load:LoadNumber) -> Result<(), ParseErrors> {
-
-        let value_danfe_parsed = value.clone().as_str().parse::<u128>()?; 
+    pub fn add_between(&mut self, value:DANFE, document:DocumentNumber, load:LoadNumber) {
 
         let new_node = Node{
-            value: value.clone(),
-            load: load,
-            value_number: value_danfe_parsed,
+            value,
+            load,
+            document,
             next: None.into(),
             previous: None.into()
         };
         
         let old_head = self.head.borrow().clone();
         if let Some(head) = old_head {
-            if head.value_number >= new_node.value_number{
+            if head.document >= new_node.document{
                 self.switch_head(new_node);
-                return Ok(()); }
+                return; }
 
             let mut current_node = head;
             loop{
                 let current_node_next_ref = current_node.next.borrow().clone();
                 if let Some(next_node) = current_node_next_ref {
                     
-                    if next_node.value_number >= new_node.value_number {
+                    if next_node.document >= new_node.document {
                         break;
                     }
 
                     current_node = next_node;
                 }else{
-                    self.switch_tail(&mut current_node, new_node);
-                    return Ok(());
+                    self.switch_tail(&current_node, new_node);
+                    return;
                 }
 
             }
 
-            self.insert_middle(&mut current_node, new_node);
+            self.insert_middle(&current_node, new_node);
 
             
 
         }else{
             self.add_head(new_node);
         }
-
-        Ok(())
-
-        
     }
 
 }
@@ -252,6 +546,7 @@ impl LinkedList {
 impl Load {
     pub fn update_load_delivery_data(&mut self){
         self.calculate_total_cubicage(); 
+        self.calculate_total_value_and_weight();
         self.calculate_price_for_each_delivery();
         self.concat_bonus();
     }
@@ -280,27 +575,44 @@ impl Load {
             .expect("Failed on get total value");
     }
 
+    fn calculate_total_value_and_weight(&mut self){
+        self.total_value = round_price(self.deliveries.iter().map(|delivery| delivery.value).sum());
+        self.total_weight = self.deliveries.iter().map(|delivery| delivery.gross_weight).sum();
+    }
+
+    // deliveries to the same recipient (by CNPJ/CPF, xNome when there's none) become one
     fn concat_bonus(&mut self){
-        let mut names = HashMap::<String, usize>::new();
+        let mut recipients = HashMap::<String, usize>::new();
         let mut to_remove = Vec::<usize>::new();
 
         let mut new_data = self.deliveries.clone();
 
         for (index,delivery) in self.deliveries.iter().enumerate(){
 
-            match names.get(&delivery.to){
+            let recipient = match delivery.recipient.key() {
+                key if key.is_empty() => normalise_name(&delivery.to),
+                key => key
+            };
+
+            match recipients.get(&recipient){
                 Some(value) => {
                     let first_delivery = &mut new_data[*value];
                     first_delivery.price = round_price(first_delivery.price + delivery.price);
                     first_delivery.quantity += delivery.quantity;
                     first_delivery.cubicage += delivery.cubicage;
-
-                    first_delivery.key = vec![first_delivery.key.clone(), delivery.key.clone()].concat();
-                    first_delivery.danfe = vec![first_delivery.danfe.clone(), delivery.danfe.clone()].concat();
+                    first_delivery.value = round_price(first_delivery.value + delivery.value);
+                    first_delivery.net_weight += delivery.net_weight;
+                    first_delivery.gross_weight += delivery.gross_weight;
+
+                    first_delivery.key = [first_delivery.key.clone(), delivery.key.clone()].concat();
+                    first_delivery.danfe = [first_delivery.danfe.clone(), delivery.danfe.clone()].concat();
+                    first_delivery.document = [first_delivery.document.clone(), delivery.document.clone()].concat();
+                    first_delivery.volumes = [first_delivery.volumes.clone(), delivery.volumes.clone()].concat();
+                    first_delivery.corrections = [first_delivery.corrections.clone(), delivery.corrections.clone()].concat();
                     to_remove.push(index);
                 },
                 None => {
-                    names.insert(delivery.to.clone(), index);
+                    recipients.insert(recipient, index);
                     continue;
                 }
             };
@@ -318,23 +630,42 @@ impl Load {
 }
 
 
+impl Delivery {
+    // quantity and weight by species (esp), one line for each unit type of the CT-e infQ
+    pub fn quantities_by_species(&self) -> Vec<(String, Quantity, Weight)> {
+        let mut lines : Vec<(String, Quantity, Weight)> = vec![];
+
+        for volume in &self.volumes {
+            let quantity = volume.quantity.unwrap_or(0);
+            let weight = volume.gross_weight.unwrap_or(0.0);
+
+            match lines.iter_mut().find(|(species, _, _)| *species == volume.species) {
+                Some(line) => {
+                    line.1 += quantity;
+                    line.2 += weight;
+                },
+                None => lines.push((volume.species.clone(), quantity, weight))
+            }
+        }
+
+        lines
+    }
+}
+
 impl LoadsDataByCarrier{ 
     pub fn get_correct_sequence_of_loads(&mut self){
         let mut linked_list = LinkedList{head:None.into()};
         
         for (load,val) in self.loads.iter(){
-            let _ = linked_list.add_between(val.deliveries[0].danfe[0].clone(), *load);
+            let first_delivery = &val.deliveries[0];
+            linked_list.add_between(first_delivery.danfe[0].clone(), first_delivery.document[0], *load);
         }
         
         let mut loads = vec![]; 
         let mut current_node = linked_list.head.borrow().clone();
-        loop{
-            if let Some(value) = current_node{
-                loads.push(value.load);
-                current_node = value.next.borrow().clone();
-            }else{
-                break;
-            }
+        while let Some(value) = current_node{
+            loads.push(value.load);
+            current_node = value.next.borrow().clone();
         }
 
         self.sequence = loads;        
@@ -350,16 +681,20 @@ impl LoadsDataByCarrier{
 mod tests{
     
     use super::*;
+
+    fn document(series:Series, number:NFNumber) -> DocumentNumber {
+        DocumentNumber{series, number}
+    }
     
     #[test]
     fn test_linkedlist_add_head(){
         let mut list = LinkedList{head:None.into()};
 
-        if let Some(_) = *list.head.borrow() {
+        if list.head.borrow().is_some() {
             panic!("Should be none");
         }
 
-        let _ = list.add_between(String::from("12345"),0);
+        list.add_between(String::from("12345"), document(1, 12345), 0);
         if let Some(data) = &*list.head.borrow(){
             assert_eq!(data.value, String::from("12345"));
         }
@@ -368,8 +703,8 @@ mod tests{
     #[test]
     fn test_linkedlist_switch_head(){
         let mut list = LinkedList{head:None.into()};
-        let _ = list.add_between(String::from("12345"),0);
-        let _ = list.add_between(String::from("00001"),0);
+        list.add_between(String::from("12345"), document(1, 12345), 0);
+        list.add_between(String::from("00001"), document(1, 1), 0);
 
         if let Some(data) = &*list.head.borrow(){
             assert_eq!(data.value, String::from("00001"));
@@ -387,8 +722,8 @@ mod tests{
     #[test]
     fn test_linkedlist_add_tail(){
         let mut list = LinkedList{head:None.into()};
-        let _ = list.add_between(String::from("00001"),0);
-        let _ = list.add_between(String::from("12345"),0);
+        list.add_between(String::from("00001"), document(1, 1), 0);
+        list.add_between(String::from("12345"), document(1, 12345), 0);
 
         if let Some(data) = &*list.head.borrow(){
             assert_eq!(data.value, String::from("00001"));
@@ -408,9 +743,9 @@ mod tests{
     #[test]
     fn test_linkedlist_add_in_the_middle(){
         let mut list = LinkedList{head:None.into()};
-        let _ = list.add_between(String::from("00001"),0);
-        let _ = list.add_between(String::from("22345"),0);
-        let _ = list.add_between(String::from("12345"),0);
+        list.add_between(String::from("00001"), document(1, 1), 0);
+        list.add_between(String::from("22345"), document(1, 22345), 0);
+        list.add_between(String::from("12345"), document(1, 12345), 0);
 
         if let Some(data) = &*list.head.borrow(){
             assert_eq!(data.value, String::from("00001"));
@@ -431,56 +766,83 @@ mod tests{
     }
 
 
+    #[test]
+    fn test_linkedlist_orders_by_series_first(){
+        let mut list = LinkedList{head:None.into()};
+        list.add_between(String::from("002-1"), document(2, 1), 2);
+        list.add_between(String::from("001-99999"), document(1, 99999), 1);
+        list.add_between(String::from("001-5"), document(1, 5), 0);
+
+        let mut loads = vec![];
+        let mut current_node = list.head.borrow().clone();
+        while let Some(node) = current_node {
+            loads.push(node.load);
+            current_node = node.next.borrow().clone();
+        }
+
+        assert_eq!(loads, vec![0, 1, 2]);
+    }
+
     #[test]
     fn test_hashmap_get_loads_sequence(){
         let mut data = 
             LoadsDataByCarrier{
+                carrier: String::from("test"),
                 email: String::from(""),
                 loads:HashMap::from([
                     (4,Load{
                         deliveries: vec![
                             Delivery{
                                 danfe: vec![String::from("8")],
+                                document: vec![document(1, 8)],
                                 key: vec![],
                                 to: String::from("D"),
                                 quantity:1,
                                 price: 10.0,
-                                cubicage:0.3
+                                cubicage:0.3,
+                                ..Default::default()
                             }
                         ],
                         license_plate:String::from("1"),
                         total_price:10.0,
                         total_cubicage:0.0,
+                        ..Default::default()
                     }),
                     (3,Load{
                         deliveries: vec![
                             Delivery{
                                 danfe: vec![String::from("3")],
+                                document: vec![document(1, 3)],
                                 key: vec![],
                                 to: String::from("D"),
                                 quantity:1,
                                 price: 10.0,
-                                cubicage:0.3
+                                cubicage:0.3,
+                                ..Default::default()
                             }
                         ],
                         license_plate:String::from("1"),
                         total_price:10.0,
                         total_cubicage:0.0,
+                        ..Default::default()
                     }),
                     (1,Load{
                         deliveries: vec![
                             Delivery{
                                 danfe: vec![String::from("5")],
+                                document: vec![document(1, 5)],
                                 key: vec![],
                                 to: String::from("D"),
                                 quantity:1,
                                 price: 10.0,
-                                cubicage:0.3
+                                cubicage:0.3,
+                                ..Default::default()
                             }
                         ],
                         license_plate:String::from("1"),
                         total_price:10.0,
                         total_cubicage:0.0,
+                        ..Default::default()
                     }),
                 ]),
             sequence: vec![],
@@ -491,4 +853,106 @@ mod tests{
         assert_eq!(data.sequence[1], 1);
         assert_eq!(data.sequence[2], 4);
     }
+
+    #[test]
+    fn test_load_totals_and_bonus(){
+        let delivery = Delivery{
+            danfe: vec![String::from("1")],
+            to: String::from("A"),
+            quantity: 2,
+            cubicage: 1.0,
+            value: 100.10,
+            net_weight: 9.0,
+            gross_weight: 10.0,
+            ..Default::default()
+        };
+
+        let mut load = Load{
+            deliveries: vec![
+                delivery.clone(),
+                Delivery{
+                    danfe: vec![String::from("2")],
+                    to: String::from("B"),
+                    value: 50.0,
+                    gross_weight: 5.5,
+                    cubicage: 1.0,
+                    ..Default::default()
+                },
+                Delivery{
+                    danfe: vec![String::from("3")],
+                    value: 200.25,
+                    net_weight: 1.0,
+                    gross_weight: 2.0,
+                    ..delivery
+                },
+            ],
+            total_price: 300.0,
+            ..Default::default()
+        };
+
+        load.update_load_delivery_data();
+
+        assert_eq!(load.total_value, 350.35);
+        assert_eq!(load.total_weight, 17.5);
+        assert_eq!(load.deliveries.len(), 2);
+
+        let bonus = &load.deliveries[0];
+        assert_eq!(bonus.danfe, vec![String::from("1"), String::from("3")]);
+        assert_eq!(bonus.value, 300.35);
+        assert_eq!(bonus.net_weight, 10.0);
+        assert_eq!(bonus.gross_weight, 12.0);
+        assert_eq!(bonus.quantity, 4);
+    }
+
+    #[test]
+    fn test_bonus_by_recipient_id(){
+        let delivery = |danfe:&str, to:&str, cnpj:&str| Delivery{
+            danfe: vec![String::from(danfe)],
+            to: String::from(to),
+            recipient: Party{ name: String::from(to), cnpj: String::from(cnpj), ..Default::default() },
+            cubicage: 1.0,
+            ..Default::default()
+        };
+
+        let mut load = Load{
+            deliveries: vec![
+                delivery("1", "MERCADO CENTRAL", "11222333000181"),
+                delivery("2", "MERCADO CENTRAL", "12ABC34501DE35"),
+                delivery("3", "Mercado Central Ltda.", "11.222.333/0001-81"),
+                delivery("4", "Padaria", ""),
+                delivery("5", "PADARIA", ""),
+            ],
+            total_price: 100.0,
+            ..Default::default()
+        };
+
+        load.update_load_delivery_data();
+
+        let danfes : Vec<Vec<String>> = load.deliveries.iter().map(|delivery| delivery.danfe.clone()).collect();
+        assert_eq!(danfes, vec![
+            vec![String::from("1"), String::from("3")],
+            vec![String::from("2")],
+            vec![String::from("4"), String::from("5")],
+        ]);
+    }
+
+    #[test]
+    fn test_quantities_by_species(){
+        let volume = |quantity:Quantity, species:&str, weight:Weight| Volume{
+            quantity: Some(quantity),
+            species: String::from(species),
+            gross_weight: Some(weight),
+            ..Default::default()
+        };
+
+        let delivery = Delivery{
+            volumes: vec![volume(2, "CAIXA", 10.0), volume(1, "PALLET", 300.0), volume(3, "CAIXA", 5.5)],
+            ..Default::default()
+        };
+
+        assert_eq!(delivery.quantities_by_species(), vec![
+            (String::from("CAIXA"), 5, 15.5),
+            (String::from("PALLET"), 1, 300.0),
+        ]);
+    }
 }